@@ -0,0 +1,22 @@
+//! Times `convert_smf` and `serialize_commands` separately, run with `cargo bench`.
+//! assets/large.mid has 16 channels of 1000 notes each, with volume and pitch bend changes and a few tempo changes
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use midi2psx::{convert_smf, serialize_commands, ConvertOptions};
+use midly::Smf;
+use std::hint::black_box;
+
+fn convert(c: &mut Criterion) {
+    let options = ConvertOptions::default();
+
+    for path in ["assets/test1.mid", "assets/test3.mid", "assets/large.mid"] {
+        let bytes = std::fs::read(path).expect("Failed to read benchmark input");
+        let smf = Smf::parse(&bytes).expect("Failed to parse benchmark input");
+        c.bench_function(&format!("convert_smf {path}"), |b| b.iter(|| convert_smf(black_box(&smf), &options)));
+        let commands = convert_smf(&smf, &options).unwrap();
+        c.bench_function(&format!("serialize_commands {path}"), |b| b.iter(|| serialize_commands(black_box(&commands))));
+    }
+}
+
+criterion_group!(benches, convert);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FlanSeqCommand {
+    // Channel commands
+    ReleaseNote{channel: u8, key: u8},
+    PlayNote{channel: u8, key: u8, velocity: u8},
+    SetChannelVolume{channel: u8, volume: u8},
+    SetChannelPanning{channel: u8, panning:u8},
+    SetChannelPitch{channel: u8, pitch: i16},
+    SetChannelInstrument{channel: u8, index: u8},
+    SetChannelAftertouch{channel: u8, pressure: u8}, // opcode 0x60 | channel, channel pressure applied to all notes on the channel
+    SetChannelModulation{channel: u8, depth: u8},    // opcode 0x70 | channel, vibrato depth from the modulation wheel
+
+    // Extended channel commands, all encoded as opcode 0x90 | channel followed by a sub-opcode byte
+    SetChannelSustain{channel: u8, on: bool},         // sub-opcode 0x00. Note releases are not deferred here, the player should hold released notes while sustain is on
+    SetChannelParam{channel: u8, param: u16, value: u16}, // sub-opcode 0x01, raw 14-bit NRPN number and data entry value, both little endian
+    SetChannelTranspose{channel: u8, semitones: i8},  // sub-opcode 0x02, semitone offset the player adds to the key of every note on the channel
+    SetChannelSostenuto{channel: u8, on: bool},       // sub-opcode 0x03. Only notes already held when sostenuto turns on should be sustained, the player has to track which ones
+    SetChannelSoftPedal{channel: u8, on: bool},       // sub-opcode 0x04. The player should play notes started while it's on more quietly, the amount is up to the player
+    SetChannelPortamento{channel: u8, on: bool, time: u8}, // sub-opcode 0x05, glide between notes, time is the raw CC5 value
+    SetChannelPortamentoSource{channel: u8, key: u8}, // sub-opcode 0x06, the next note glides from this key instead of the previous note, from CC84
+    ReleaseNoteVelocity{channel: u8, key: u8, velocity: u8}, // sub-opcode 0x07, same as ReleaseNote but with the NoteOff velocity, for instruments that shape the release with it
+    RetriggerNote{channel: u8, key: u8, velocity: u8}, // sub-opcode 0x08, a ReleaseNote directly followed by a PlayNote of the same key, the player restarts the envelope
+    SetNoteAftertouch{channel: u8, key: u8, pressure: u8}, // sub-opcode 0x09, polyphonic key pressure for a single playing note, players without per-note pressure can treat it as channel pressure
+
+    // General commands
+    SetTempo{tempo: u16},
+    WaitTicks{index_into_lut: usize},
+    WaitTicksLong{ticks: u16}, // opcode 0xFC, followed by the tick count in little endian, for gaps too long for WaitTicks
+    SetTimeSignature{numerator: u8, denominator: u8},
+    SetMasterVolume{volume: u8}, // opcode 0xFA, followed by the volume. Scales the volume of every channel, from the universal SysEx master volume message
+    Marker{id: u8}, // opcode 0xFB, followed by the id. Fires a game event, ids come from the [markers] section of the instrument map
+    SetLoopStart,
+    JumpToLoopStart,
+
+    // Not an actual command, nothing gets serialized for it. When building a .dss file, the commands after it go into a new section
+    SectionStart,
+}
+
+impl FlanSeqCommand {
+    pub fn serialize(&self) -> Vec<u8> {
+        match *self {
+            FlanSeqCommand::ReleaseNote         { channel, key } =>               vec![channel, key],
+            FlanSeqCommand::PlayNote            { channel, key, velocity } => vec![0x10 | channel, key, velocity],
+            FlanSeqCommand::SetChannelVolume    { channel, volume } =>            vec![0x20 | channel, volume],
+            FlanSeqCommand::SetChannelPanning   { channel, panning } =>           vec![0x30 | channel, panning],
+            FlanSeqCommand::SetChannelPitch     { channel, pitch } => {
+                let pitch_bytes = pitch.to_le_bytes();
+                vec![0x40 | channel, pitch_bytes[0], pitch_bytes[1]]
+            },
+            FlanSeqCommand::SetChannelInstrument { channel, index } =>            vec![0x50 | channel, index],
+            FlanSeqCommand::SetChannelAftertouch { channel, pressure } =>         vec![0x60 | channel, pressure],
+            FlanSeqCommand::SetChannelModulation { channel, depth } =>            vec![0x70 | channel, depth],
+            FlanSeqCommand::SetChannelSustain   { channel, on } =>                vec![0x90 | channel, 0x00, on as u8],
+            FlanSeqCommand::SetChannelParam     { channel, param, value } => {
+                let param_bytes = param.to_le_bytes();
+                let value_bytes = value.to_le_bytes();
+                vec![0x90 | channel, 0x01, param_bytes[0], param_bytes[1], value_bytes[0], value_bytes[1]]
+            },
+            FlanSeqCommand::SetChannelTranspose { channel, semitones } =>         vec![0x90 | channel, 0x02, semitones as u8],
+            FlanSeqCommand::SetChannelSostenuto { channel, on } =>                vec![0x90 | channel, 0x03, on as u8],
+            FlanSeqCommand::SetChannelSoftPedal { channel, on } =>                vec![0x90 | channel, 0x04, on as u8],
+            FlanSeqCommand::SetChannelPortamento { channel, on, time } =>         vec![0x90 | channel, 0x05, on as u8, time],
+            FlanSeqCommand::SetChannelPortamentoSource { channel, key } =>        vec![0x90 | channel, 0x06, key],
+            FlanSeqCommand::ReleaseNoteVelocity { channel, key, velocity } =>     vec![0x90 | channel, 0x07, key, velocity],
+            FlanSeqCommand::RetriggerNote { channel, key, velocity } =>           vec![0x90 | channel, 0x08, key, velocity],
+            FlanSeqCommand::SetNoteAftertouch { channel, key, pressure } =>       vec![0x90 | channel, 0x09, key, pressure],
+            FlanSeqCommand::SetTempo            { tempo } =>                         vec![0x80 | (tempo >> 8) as u8, (tempo & 0xFF) as u8],
+            FlanSeqCommand::WaitTicks { index_into_lut } =>                        vec![0xA0 + index_into_lut as u8],
+            FlanSeqCommand::WaitTicksLong { ticks } => {
+                let tick_bytes = ticks.to_le_bytes();
+                vec![0xFC, tick_bytes[0], tick_bytes[1]]
+            },
+            FlanSeqCommand::SetMasterVolume { volume } =>                         vec![0xFA, volume],
+            FlanSeqCommand::Marker { id } =>                                      vec![0xFB, id],
+            FlanSeqCommand::SetTimeSignature { numerator, denominator } =>        vec![0xFD, numerator, denominator],
+            FlanSeqCommand::SetLoopStart =>                                               vec![0xFE],
+            FlanSeqCommand::JumpToLoopStart =>                                            vec![0xFF],
+            FlanSeqCommand::SectionStart =>                                               vec![],
+        }
+    }
+
+    /// Decodes the command at the start of `bytes`, returning it along with its size in bytes.
+    /// Returns `None` if the opcode is unknown or the command is cut off.
+    pub fn deserialize(bytes: &[u8]) -> Option<(FlanSeqCommand, usize)> {
+        let opcode = *bytes.first()?;
+        let channel = opcode & 0x0F;
+        let command = match opcode {
+            0x00..=0x0F => FlanSeqCommand::ReleaseNote          { channel, key: *bytes.get(1)? },
+            0x10..=0x1F => FlanSeqCommand::PlayNote             { channel, key: *bytes.get(1)?, velocity: *bytes.get(2)? },
+            0x20..=0x2F => FlanSeqCommand::SetChannelVolume     { channel, volume: *bytes.get(1)? },
+            0x30..=0x3F => FlanSeqCommand::SetChannelPanning    { channel, panning: *bytes.get(1)? },
+            0x40..=0x4F => FlanSeqCommand::SetChannelPitch      { channel, pitch: i16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?]) },
+            0x50..=0x5F => FlanSeqCommand::SetChannelInstrument { channel, index: *bytes.get(1)? },
+            0x60..=0x6F => FlanSeqCommand::SetChannelAftertouch { channel, pressure: *bytes.get(1)? },
+            0x70..=0x7F => FlanSeqCommand::SetChannelModulation { channel, depth: *bytes.get(1)? },
+            0x80..=0x8F => FlanSeqCommand::SetTempo             { tempo: ((channel as u16) << 8) | *bytes.get(1)? as u16 },
+            0x90..=0x9F => match *bytes.get(1)? {
+                0x00 => FlanSeqCommand::SetChannelSustain       { channel, on: *bytes.get(2)? != 0 },
+                0x01 => FlanSeqCommand::SetChannelParam         { channel, param: u16::from_le_bytes([*bytes.get(2)?, *bytes.get(3)?]), value: u16::from_le_bytes([*bytes.get(4)?, *bytes.get(5)?]) },
+                0x02 => FlanSeqCommand::SetChannelTranspose     { channel, semitones: *bytes.get(2)? as i8 },
+                0x03 => FlanSeqCommand::SetChannelSostenuto     { channel, on: *bytes.get(2)? != 0 },
+                0x04 => FlanSeqCommand::SetChannelSoftPedal     { channel, on: *bytes.get(2)? != 0 },
+                0x05 => FlanSeqCommand::SetChannelPortamento    { channel, on: *bytes.get(2)? != 0, time: *bytes.get(3)? },
+                0x06 => FlanSeqCommand::SetChannelPortamentoSource { channel, key: *bytes.get(2)? },
+                0x07 => FlanSeqCommand::ReleaseNoteVelocity     { channel, key: *bytes.get(2)?, velocity: *bytes.get(3)? },
+                0x08 => FlanSeqCommand::RetriggerNote           { channel, key: *bytes.get(2)?, velocity: *bytes.get(3)? },
+                0x09 => FlanSeqCommand::SetNoteAftertouch       { channel, key: *bytes.get(2)?, pressure: *bytes.get(3)? },
+                _ => return None,
+            },
+            0xA0..=0xBF => FlanSeqCommand::WaitTicks            { index_into_lut: (opcode - 0xA0) as usize },
+            0xFA =>        FlanSeqCommand::SetMasterVolume      { volume: *bytes.get(1)? },
+            0xFB =>        FlanSeqCommand::Marker               { id: *bytes.get(1)? },
+            0xFC =>        FlanSeqCommand::WaitTicksLong        { ticks: u16::from_le_bytes([*bytes.get(1)?, *bytes.get(2)?]) },
+            0xFD =>        FlanSeqCommand::SetTimeSignature     { numerator: *bytes.get(1)?, denominator: *bytes.get(2)? },
+            0xFE =>        FlanSeqCommand::SetLoopStart,
+            0xFF =>        FlanSeqCommand::JumpToLoopStart,
+            _ => return None,
+        };
+        let size = command.serialize().len();
+        Some((command, size))
+    }
+
+    /// Name of the command variant, for diagnostics
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FlanSeqCommand::ReleaseNote { .. } => "ReleaseNote",
+            FlanSeqCommand::PlayNote { .. } => "PlayNote",
+            FlanSeqCommand::SetChannelVolume { .. } => "SetChannelVolume",
+            FlanSeqCommand::SetChannelPanning { .. } => "SetChannelPanning",
+            FlanSeqCommand::SetChannelPitch { .. } => "SetChannelPitch",
+            FlanSeqCommand::SetChannelInstrument { .. } => "SetChannelInstrument",
+            FlanSeqCommand::SetChannelAftertouch { .. } => "SetChannelAftertouch",
+            FlanSeqCommand::SetChannelModulation { .. } => "SetChannelModulation",
+            FlanSeqCommand::SetChannelSustain { .. } => "SetChannelSustain",
+            FlanSeqCommand::SetChannelParam { .. } => "SetChannelParam",
+            FlanSeqCommand::SetChannelTranspose { .. } => "SetChannelTranspose",
+            FlanSeqCommand::SetChannelSostenuto { .. } => "SetChannelSostenuto",
+            FlanSeqCommand::SetChannelSoftPedal { .. } => "SetChannelSoftPedal",
+            FlanSeqCommand::SetChannelPortamento { .. } => "SetChannelPortamento",
+            FlanSeqCommand::SetChannelPortamentoSource { .. } => "SetChannelPortamentoSource",
+            FlanSeqCommand::ReleaseNoteVelocity { .. } => "ReleaseNoteVelocity",
+            FlanSeqCommand::RetriggerNote { .. } => "RetriggerNote",
+            FlanSeqCommand::SetNoteAftertouch { .. } => "SetNoteAftertouch",
+            FlanSeqCommand::SetTempo { .. } => "SetTempo",
+            FlanSeqCommand::WaitTicks { .. } => "WaitTicks",
+            FlanSeqCommand::WaitTicksLong { .. } => "WaitTicksLong",
+            FlanSeqCommand::SetTimeSignature { .. } => "SetTimeSignature",
+            FlanSeqCommand::Marker { .. } => "Marker",
+            FlanSeqCommand::SetMasterVolume { .. } => "SetMasterVolume",
+            FlanSeqCommand::SetLoopStart => "SetLoopStart",
+            FlanSeqCommand::JumpToLoopStart => "JumpToLoopStart",
+            FlanSeqCommand::SectionStart => "SectionStart",
+        }
+    }
+
+    /// The channel this command applies to, if it's a channel command
+    pub fn channel(&self) -> Option<u8> {
+        let mut command = *self;
+        command.channel_mut().copied()
+    }
+
+    /// Mutable access to the channel this command applies to, if it's a channel command
+    pub fn channel_mut(&mut self) -> Option<&mut u8> {
+        match self {
+            FlanSeqCommand::ReleaseNote { channel, .. }
+            | FlanSeqCommand::PlayNote { channel, .. }
+            | FlanSeqCommand::SetChannelVolume { channel, .. }
+            | FlanSeqCommand::SetChannelPanning { channel, .. }
+            | FlanSeqCommand::SetChannelPitch { channel, .. }
+            | FlanSeqCommand::SetChannelInstrument { channel, .. }
+            | FlanSeqCommand::SetChannelAftertouch { channel, .. }
+            | FlanSeqCommand::SetChannelModulation { channel, .. }
+            | FlanSeqCommand::SetChannelSustain { channel, .. }
+            | FlanSeqCommand::SetChannelParam { channel, .. }
+            | FlanSeqCommand::SetChannelTranspose { channel, .. }
+            | FlanSeqCommand::SetChannelSostenuto { channel, .. }
+            | FlanSeqCommand::SetChannelSoftPedal { channel, .. }
+            | FlanSeqCommand::SetChannelPortamento { channel, .. }
+            | FlanSeqCommand::SetChannelPortamentoSource { channel, .. }
+            | FlanSeqCommand::ReleaseNoteVelocity { channel, .. }
+            | FlanSeqCommand::RetriggerNote { channel, .. }
+            | FlanSeqCommand::SetNoteAftertouch { channel, .. } => Some(channel),
+            FlanSeqCommand::SetTempo { .. }
+            | FlanSeqCommand::WaitTicks { .. }
+            | FlanSeqCommand::WaitTicksLong { .. }
+            | FlanSeqCommand::SetTimeSignature { .. }
+            | FlanSeqCommand::Marker { .. }
+            | FlanSeqCommand::SetMasterVolume { .. }
+            | FlanSeqCommand::SetLoopStart
+            | FlanSeqCommand::JumpToLoopStart
+            | FlanSeqCommand::SectionStart => None,
+        }
+    }
+
+    /// How many ticks the player waits after this command, using the stock player's wait table
+    pub fn wait_ticks(&self) -> u32 {
+        self.wait_ticks_with(&WAIT_TICK_LUT)
+    }
+
+    /// How many ticks the player waits after this command, using a custom wait table
+    pub fn wait_ticks_with(&self, wait_lut: &[u16; 32]) -> u32 {
+        match *self {
+            FlanSeqCommand::WaitTicks { index_into_lut } => wait_lut[index_into_lut] as u32,
+            FlanSeqCommand::WaitTicksLong { ticks } => ticks as u32,
+            _ => 0,
+        }
+    }
+}
+
+/// Channels are packed into the low nibble of the opcode, so there can be at most 16 of them
+pub const MAX_CHANNELS: u8 = 16;
+
+/// Raw tempo value per second of tick length, this matches the timer setup of the stock sequence player
+pub const TICK_LENGTH_MULTIPLIER: f64 = 49152.0;
+
+pub const WAIT_TICK_LUT: [u16; 32] = [
+    1,      2,      3,      4,      6,      8,      12,     16,
+    20,     24,     28,     32,     40,     48,     56,     64,
+    80,     96,     112,    128,    160,    192,    224,    256,
+    320,    384,    448,    512,    640,    768,    896,    1024,
+];
\ No newline at end of file
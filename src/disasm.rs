@@ -0,0 +1,39 @@
+use crate::{check_data_integrity, read_section_offsets, FlanSeqCommand, WaitLut};
+
+/// Decodes a .dss file into a human readable listing, one command per line,
+/// along with its offset in the file and the tick position it executes at, with waits as long as they are in the given wait table
+pub fn disassemble(bytes: &[u8], wait_lut: &WaitLut) -> Result<Vec<String>, String> {
+    let section_offsets = read_section_offsets(bytes).ok_or("Not a valid .dss file")?;
+    let mut lines = Vec::new();
+
+    // Still list the commands of a damaged file, they might show where it went wrong
+    if let Err(err) = check_data_integrity(bytes) {
+        lines.push(format!("warning: {err}"));
+    }
+
+    for (section_index, &section_start) in section_offsets.iter().enumerate() {
+        let section_end = section_offsets.get(section_index + 1).copied().unwrap_or(bytes.len());
+        if section_start > section_end || section_end > bytes.len() {
+            return Err(format!("Section {section_index} has invalid bounds {section_start:#X}..{section_end:#X}"));
+        }
+        lines.push(format!("section {section_index}:"));
+
+        let mut offset = section_start;
+        let mut tick = 0;
+        while offset < section_end {
+            match FlanSeqCommand::deserialize(&bytes[offset..section_end]) {
+                Some((command, size)) => {
+                    lines.push(format!("  {offset:08X}  tick {tick:>8}  {command:?}"));
+                    tick += command.wait_ticks_with(&wait_lut.0);
+                    offset += size;
+                }
+                None => {
+                    lines.push(format!("  {offset:08X}  tick {tick:>8}  unknown or truncated command, opcode {:#04X}", bytes[offset]));
+                    offset += 1;
+                }
+            }
+        }
+    }
+
+    Ok(lines)
+}
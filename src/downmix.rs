@@ -0,0 +1,42 @@
+use std::str::FromStr;
+
+/// MIDI channels that get folded onto fewer player channels, each of which plays one note at a time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownmixGroup {
+    pub sources: Vec<u8>,
+    pub targets: Vec<u8>,
+}
+
+/// Parses a group like "0,1,2=0" or "3,4,5=1,2"
+impl FromStr for DownmixGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_channels = |list: &str| list.split(',')
+            .map(|channel| channel.trim().parse::<u8>().ok().filter(|&channel| channel < 16))
+            .collect::<Option<Vec<u8>>>();
+        let group = s.split_once('=').and_then(|(sources, targets)| Some(DownmixGroup { sources: parse_channels(sources)?, targets: parse_channels(targets)? }));
+        group.ok_or(format!("Invalid downmix group \"{s}\", expected channels=channels, e.g. 0,1,2=0"))
+    }
+}
+
+/// Which of a group's player channels a new note goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownmixPolicy {
+    /// A channel that isn't playing anything, or else the one whose note started first, so the newest note always plays
+    LastNote,
+    /// The next channel in turn, whether it's still playing or not
+    RoundRobin,
+}
+
+impl FromStr for DownmixPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last-note" => Ok(DownmixPolicy::LastNote),
+            "round-robin" => Ok(DownmixPolicy::RoundRobin),
+            _ => Err(format!("Unknown downmix policy \"{s}\"")),
+        }
+    }
+}
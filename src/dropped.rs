@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Counts of MIDI events that were dropped during conversion because the player has nothing to map them to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DroppedEvents {
+    /// Number of dropped controller events, by controller number
+    pub controllers: BTreeMap<u8, usize>,
+    /// Number of dropped meta events, by meta event type
+    pub meta_events: BTreeMap<String, usize>,
+    /// Number of dropped system exclusive, escape and other events, by event type
+    pub other_events: BTreeMap<String, usize>,
+}
+
+impl DroppedEvents {
+    pub fn add_controller(&mut self, controller: u8) {
+        *self.controllers.entry(controller).or_default() += 1;
+    }
+
+    pub fn add_meta_event(&mut self, message: &midly::MetaMessage) {
+        *self.meta_events.entry(variant_name(message)).or_default() += 1;
+    }
+
+    pub fn add_other_event(&mut self, event: &midly::TrackEventKind) {
+        *self.other_events.entry(variant_name(event)).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.controllers.is_empty() && self.meta_events.is_empty() && self.other_events.is_empty()
+    }
+}
+
+/// Name of an enum variant, taken from its debug representation
+fn variant_name(value: &impl fmt::Debug) -> String {
+    let debug = format!("{value:?}");
+    let end = debug.find(['(', '{', ' ']).unwrap_or(debug.len());
+    debug[..end].to_string()
+}
+
+/// Writes a line like "Dropped 42 unsupported controller events (CC1 x30, CC74 x12)"
+fn write_summary_line<K>(f: &mut fmt::Formatter, kind: &str, counts: &BTreeMap<K, usize>, name: impl Fn(&K) -> String) -> fmt::Result {
+    if counts.is_empty() {
+        return Ok(());
+    }
+    let total: usize = counts.values().sum();
+    let details: Vec<String> = counts.iter().map(|(key, count)| format!("{} x{count}", name(key))).collect();
+    writeln!(f, "Dropped {total} unsupported {kind} events ({})", details.join(", "))
+}
+
+impl fmt::Display for DroppedEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_summary_line(f, "controller", &self.controllers, |controller| format!("CC{controller}"))?;
+        write_summary_line(f, "meta", &self.meta_events, String::clone)?;
+        write_summary_line(f, "other", &self.other_events, String::clone)
+    }
+}
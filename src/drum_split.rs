@@ -0,0 +1,45 @@
+use std::str::FromStr;
+
+/// Groups of drum keys on channel 9 that each get moved to a channel of their own,
+/// so drums in different groups don't cut each other off on the player
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrumSplit {
+    /// MIDI keys in each group, keys that aren't in any group stay on channel 9
+    pub groups: Vec<Vec<u8>>,
+}
+
+impl Default for DrumSplit {
+    /// General MIDI kicks, snares and claps, hi-hats, toms and cymbals
+    fn default() -> Self {
+        Self {
+            groups: vec![
+                vec![35, 36],
+                vec![37, 38, 39, 40],
+                vec![42, 44, 46],
+                vec![41, 43, 45, 47, 48, 50],
+                vec![49, 51, 52, 53, 55, 57, 59],
+            ],
+        }
+    }
+}
+
+/// Parses groups separated by slashes, each one a comma separated list of keys and key ranges, e.g. "35-36/37-40/42,44,46"
+impl FromStr for DrumSplit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_key = |key: &str| key.trim().parse::<u8>().ok().filter(|&key| key < 128).ok_or(format!("Invalid drum key \"{}\"", key.trim()));
+        let mut groups = Vec::new();
+        for group in s.split('/') {
+            let mut keys = Vec::new();
+            for item in group.split(',') {
+                match item.split_once('-') {
+                    Some((first, last)) => keys.extend(parse_key(first)?..=parse_key(last)?),
+                    None => keys.push(parse_key(item)?),
+                }
+            }
+            groups.push(keys);
+        }
+        Ok(Self { groups })
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Reasons a conversion can fail
+#[derive(Debug)]
+pub enum ConvertError {
+    /// A file couldn't be read, "-" stands for stdin
+    Io { path: PathBuf, source: io::Error },
+    /// A file couldn't be written, "-" stands for stdout
+    Write { path: PathBuf, source: io::Error },
+    /// The input isn't a valid MIDI file
+    Parse(midly::Error),
+    /// The instrument map file has a syntax error
+    InstrumentMap(String),
+    /// The wait table file isn't a valid table of wait lengths
+    WaitLut(String),
+    /// The MIDI file's time division can't be used for one of the options, e.g. beats with SMPTE timecode
+    UnsupportedTiming(String),
+    /// Commands ended up on a channel the player doesn't have, only an error in strict mode
+    ChannelOverflow { channel: u8, max_channels: u8 },
+    /// A tempo doesn't fit in the player's tempo value, only an error in strict mode
+    TempoOutOfRange(String),
+    /// The MIDI file uses this many events the player can't represent, only an error in strict mode
+    UnsupportedEvents(usize),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::Io { path, source } => write!(f, "failed to read {} ({source})", path.display()),
+            ConvertError::Write { path, source } => write!(f, "failed to write {} ({source})", path.display()),
+            ConvertError::Parse(err) => match err.kind() {
+                midly::ErrorKind::Invalid(_) => write!(f, "not a valid MIDI file ({err})"),
+                midly::ErrorKind::Malformed(_) => write!(f, "file is corrupted ({err})"),
+            },
+            ConvertError::InstrumentMap(message) => write!(f, "invalid instrument map ({message})"),
+            ConvertError::WaitLut(message) => write!(f, "invalid wait table ({message})"),
+            ConvertError::UnsupportedTiming(message) => write!(f, "{message}"),
+            ConvertError::TempoOutOfRange(message) => write!(f, "{message}"),
+            ConvertError::ChannelOverflow { channel, max_channels } => write!(f, "channel {channel} is outside of the supported range 0-{}", *max_channels as i32 - 1),
+            ConvertError::UnsupportedEvents(count) => write!(f, "found {count} unsupported events in strict mode"),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConvertError::Io { source, .. } | ConvertError::Write { source, .. } => Some(source),
+            ConvertError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<midly::Error> for ConvertError {
+    fn from(err: midly::Error) -> Self {
+        ConvertError::Parse(err)
+    }
+}
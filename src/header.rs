@@ -0,0 +1,123 @@
+/// Size of the fixed part of the header, before the section table
+pub const HEADER_SIZE: usize = 28;
+
+/// Version of the .dss format written by this converter, bumped whenever the header layout or command set changes,
+/// so a player can refuse files with commands it doesn't know instead of misreading them.
+/// - 1: added this version field
+/// - 2: added the section data size and CRC-32 fields
+/// - 3: added ReleaseNoteVelocity (0x90 | channel, sub-opcode 0x07)
+/// - 4: added RetriggerNote (0x90 | channel, sub-opcode 0x08)
+/// - 5: added Marker (0xFB)
+/// - 6: added SetNoteAftertouch (0x90 | channel, sub-opcode 0x09)
+/// - 7: added SetMasterVolume (0xFA)
+pub const FORMAT_VERSION: u32 = 7;
+
+/// Oldest format version with the current header layout. Files from before versioning was added have the section count
+/// where the version is now, which is nearly always 1, so they're refused along with version 1 files
+const OLDEST_READABLE_VERSION: u32 = 2;
+
+/// Describes one section of sequence data in a .dss file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionInfo {
+    /// Size of the section's serialized command data in bytes
+    pub size: usize,
+}
+
+/// Builds the .dss header and section table for the given sections.
+///
+/// Layout, all values little endian, all offsets relative to the end of the fixed header:
+/// - 0x00: file magic "FDDS"
+/// - 0x04: u32 format version, see [`FORMAT_VERSION`]
+/// - 0x08: u32 number of sections
+/// - 0x0C: u32 section table offset
+/// - 0x10: u32 section data offset
+/// - 0x14: u32 total size of the section data in bytes, so a truncated file can be detected
+/// - 0x18: u32 CRC-32 (IEEE) of the section data, written by [`write_checksum`] once the data is known
+/// - section table: one u32 per section, the offset of that section relative to the section data
+///
+/// The section table directly follows the fixed header, and the section data directly follows the table.
+pub fn write_header(sections: &[SectionInfo]) -> Vec<u8> {
+    let section_table_offset = 0u32;
+    let section_data_offset = section_table_offset + 4 * sections.len() as u32;
+
+    let mut output = Vec::<u8>::new();
+    output.extend("FDDS".as_bytes());
+    output.extend(FORMAT_VERSION.to_le_bytes());
+    output.extend((sections.len() as u32).to_le_bytes());
+    output.extend(section_table_offset.to_le_bytes());
+    output.extend(section_data_offset.to_le_bytes());
+    output.extend((sections.iter().map(|section| section.size).sum::<usize>() as u32).to_le_bytes());
+    output.extend(0u32.to_le_bytes());
+
+    // Sections are stored back to back in the order they're given
+    let mut section_offset = 0u32;
+    for section in sections {
+        output.extend(section_offset.to_le_bytes());
+        section_offset += section.size as u32;
+    }
+    output
+}
+
+/// Reads the header of a .dss file, returning the absolute file offset of each section.
+/// Returns `None` if the magic is wrong, the format version has a different header layout or is newer than this converter knows about,
+/// or the header is cut off.
+pub fn read_section_offsets(bytes: &[u8]) -> Option<Vec<usize>> {
+    let read_u32 = |offset: usize| -> Option<usize> {
+        let value_bytes = bytes.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(value_bytes.try_into().unwrap()) as usize)
+    };
+
+    if bytes.get(0..4)? != "FDDS".as_bytes() {
+        return None;
+    }
+    if !(OLDEST_READABLE_VERSION as usize..=FORMAT_VERSION as usize).contains(&read_u32(0x04)?) {
+        return None;
+    }
+    let section_count = read_u32(0x08)?;
+    let section_table_offset = HEADER_SIZE + read_u32(0x0C)?;
+    let section_data_offset = HEADER_SIZE + read_u32(0x10)?;
+
+    (0..section_count)
+        .map(|index| Some(section_data_offset + read_u32(section_table_offset + 4 * index)?))
+        .collect()
+}
+
+/// Fills in the CRC-32 field of a complete .dss file, header and section data included
+pub fn write_checksum(bytes: &mut [u8]) {
+    let section_data_offset = HEADER_SIZE + u32::from_le_bytes(bytes[0x10..0x14].try_into().unwrap()) as usize;
+    let crc = crc32(&bytes[section_data_offset..]);
+    bytes[0x18..0x1C].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Checks the section data size and CRC-32 in the header of a .dss file against the actual section data
+pub fn check_data_integrity(bytes: &[u8]) -> Result<(), String> {
+    let read_u32 = |offset: usize| -> Result<u32, String> {
+        let value_bytes = bytes.get(offset..offset + 4).ok_or("Header is cut off")?;
+        Ok(u32::from_le_bytes(value_bytes.try_into().unwrap()))
+    };
+
+    let section_data_offset = HEADER_SIZE + read_u32(0x10)? as usize;
+    let section_data = bytes.get(section_data_offset..).ok_or("Section table is cut off")?;
+    let expected_size = read_u32(0x14)? as usize;
+    if section_data.len() != expected_size {
+        return Err(format!("Section data is {} bytes, but the header says {expected_size} bytes", section_data.len()));
+    }
+    let expected_crc = read_u32(0x18)?;
+    let crc = crc32(section_data);
+    if crc != expected_crc {
+        return Err(format!("Section data CRC-32 is {crc:08X}, but the header says {expected_crc:08X}"));
+    }
+    Ok(())
+}
+
+/// Standard CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), the same one zlib uses
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
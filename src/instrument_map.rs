@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+/// Mapping from MIDI program numbers to instrument indices in the PSX instrument bank,
+/// and from drum keys to the key numbers used by the PSX drum kit.
+///
+/// The file format is a simple INI-like text file, with `from = to` lines grouped in sections:
+///
+/// ```text
+/// # Melodic programs, used for every channel except 9
+/// [programs]
+/// 0 = 3
+/// 33 = 7
+/// # Programs can be limited to one bank (CC0 * 128 + CC32), these take priority
+/// 1:33 = 8
+///
+/// # Drum kits, used for programs on channel 9
+/// [drum_kits]
+/// 0 = 128
+///
+/// # Keys on channel 9, e.g. GM kick drum to key 0
+/// [drum_notes]
+/// 36 = 0
+///
+/// # Marker and cue point texts, turned into marker commands with this id
+/// [markers]
+/// chorus = 1
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstrumentMap {
+    pub programs: HashMap<u8, u8>,
+    pub banked_programs: HashMap<(u16, u8), u8>,
+    pub drum_kits: HashMap<u8, u8>,
+    pub banked_drum_kits: HashMap<(u16, u8), u8>,
+    pub drum_notes: HashMap<u8, u8>,
+    pub markers: HashMap<String, u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Programs,
+    DrumKits,
+    DrumNotes,
+    Markers,
+}
+
+impl InstrumentMap {
+    /// Looks up the instrument index for a program on a channel, preferring mappings for the selected bank
+    pub fn lookup_program(&self, channel: u8, bank: u16, program: u8) -> Option<u8> {
+        let (programs, banked_programs) = match channel {
+            9 => (&self.drum_kits, &self.banked_drum_kits),
+            _ => (&self.programs, &self.banked_programs),
+        };
+        banked_programs.get(&(bank, program)).or(programs.get(&program)).copied()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = InstrumentMap::default();
+        let mut section = None;
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                section = match name.trim() {
+                    "programs" => Some(Section::Programs),
+                    "drum_kits" => Some(Section::DrumKits),
+                    "drum_notes" => Some(Section::DrumNotes),
+                    "markers" => Some(Section::Markers),
+                    other => return Err(format!("line {line_number}: unknown section \"{other}\"")),
+                };
+                continue;
+            }
+
+            let Some(section) = section else {
+                return Err(format!("line {line_number}: mapping outside of a section"));
+            };
+            let Some((from, to)) = line.split_once('=') else {
+                return Err(format!("line {line_number}: expected \"from = to\""));
+            };
+            if section == Section::Markers {
+                let id = to.trim().parse::<u8>()
+                    .map_err(|_| format!("line {line_number}: \"{}\" is not a valid marker id", to.trim()))?;
+                map.markers.insert(from.trim().to_string(), id);
+                continue;
+            }
+            let (bank, from) = match from.split_once(':') {
+                Some((bank, from)) if section != Section::DrumNotes => {
+                    let bank = bank.trim().parse::<u16>().ok().filter(|&bank| bank < 0x4000)
+                        .ok_or(format!("line {line_number}: \"{}\" is not a valid bank number", bank.trim()))?;
+                    (Some(bank), from)
+                }
+                _ => (None, from),
+            };
+            let from = from.trim().parse::<u8>().ok().filter(|&from| from < 128)
+                .ok_or(format!("line {line_number}: \"{}\" is not a valid MIDI program or key", from.trim()))?;
+            let to = to.trim().parse::<u8>()
+                .map_err(|_| format!("line {line_number}: \"{}\" is not a valid value", to.trim()))?;
+
+            match (section, bank) {
+                (Section::Programs, None) => map.programs.insert(from, to),
+                (Section::Programs, Some(bank)) => map.banked_programs.insert((bank, from), to),
+                (Section::DrumKits, None) => map.drum_kits.insert(from, to),
+                (Section::DrumKits, Some(bank)) => map.banked_drum_kits.insert((bank, from), to),
+                (Section::DrumNotes, _) => map.drum_notes.insert(from, to),
+                (Section::Markers, _) => unreachable!(),
+            };
+        }
+
+        Ok(map)
+    }
+}
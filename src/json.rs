@@ -0,0 +1,20 @@
+use crate::{FlanSeqCommand, WaitLut};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct TimedCommand<'a> {
+    tick: u64,
+    command: &'a FlanSeqCommand,
+}
+
+/// Converts a command stream to JSON, as a list of commands along with the tick position they execute at,
+/// with waits as long as they are in the given wait table
+pub fn commands_to_json(commands: &[FlanSeqCommand], wait_lut: &WaitLut) -> String {
+    let mut tick = 0;
+    let timed_commands: Vec<TimedCommand> = commands.iter().map(|command| {
+        let timed_command = TimedCommand { tick, command };
+        tick += command.wait_ticks_with(&wait_lut.0) as u64;
+        timed_command
+    }).collect();
+    serde_json::to_string_pretty(&timed_commands).expect("Failed to serialize commands to JSON")
+}
@@ -0,0 +1,1520 @@
+use log::{debug, error, info, warn};
+use midly::Smf;
+use midly::TrackEventKind;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+mod command;
+mod disasm;
+mod downmix;
+mod dropped;
+mod drum_split;
+mod error;
+mod header;
+mod instrument_map;
+mod json;
+mod note_range;
+mod overlap;
+mod pan;
+mod stats;
+mod time_point;
+mod velocity;
+mod verify;
+mod wait_lut;
+
+pub use command::{FlanSeqCommand, MAX_CHANNELS, TICK_LENGTH_MULTIPLIER, WAIT_TICK_LUT};
+pub use disasm::disassemble;
+pub use downmix::{DownmixGroup, DownmixPolicy};
+pub use dropped::DroppedEvents;
+pub use drum_split::DrumSplit;
+pub use error::ConvertError;
+pub use header::{check_data_integrity, read_section_offsets, write_checksum, write_header, SectionInfo, FORMAT_VERSION, HEADER_SIZE};
+pub use instrument_map::InstrumentMap;
+pub use json::commands_to_json;
+pub use note_range::NoteRange;
+pub use overlap::OverlapPolicy;
+pub use pan::{map_pan, PanLaw};
+pub use stats::ConversionStats;
+pub use time_point::TimePoint;
+pub use velocity::{map_velocity, VelocityCurve};
+pub use verify::verify_commands;
+pub use wait_lut::WaitLut;
+
+/// Settings that affect how a MIDI file is converted
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Loop the whole song if the MIDI file doesn't define its own loop points
+    pub loop_song: bool,
+    /// Number of channels the sequence player supports, commands for channels beyond this are dropped
+    pub max_channels: u8,
+    /// Curve applied to note velocities
+    pub velocity_curve: VelocityCurve,
+    /// How MIDI pan positions map to the player's panning
+    pub pan_law: PanLaw,
+    /// Custom program to instrument index mapping, programs without a mapping fall back to the default
+    pub instrument_map: Option<InstrumentMap>,
+    /// Drop drum notes that aren't in the instrument map's drum note mapping, instead of passing them through
+    pub drop_unmapped_drums: bool,
+    /// Move groups of drum keys to channels of their own, or keep every drum on channel 9 if `None`
+    pub split_drums: Option<DrumSplit>,
+    /// Semitones to shift every note by, the result is clamped to 0-127
+    pub transpose: i32,
+    /// Also transpose notes on the drum channel
+    pub transpose_drums: bool,
+    /// Keys the player can play, notes outside it on channels other than the drum channel are moved into it
+    pub note_range: Option<NoteRange>,
+    /// Drop notes outside the note range instead of moving them to the nearest key in it
+    pub drop_out_of_range_notes: bool,
+    /// Fixed tempo in BPM, replacing all tempo events in the MIDI file
+    pub tempo_bpm: Option<f64>,
+    /// Tempo in BPM until the first tempo event, MIDI files without one at the start play at 120 BPM
+    pub default_tempo_bpm: f64,
+    /// Release notes that are still playing at the end of the song
+    pub release_hanging_notes: bool,
+    /// End the song at its last event, instead of waiting until the end of track like the MIDI file does
+    pub trim_tail: bool,
+    /// How to handle a key being pressed again while it's still held, or `None` to pass both notes through as they are
+    pub overlap: Option<OverlapPolicy>,
+    /// Snap events to a grid of this note division, e.g. 16 for sixteenth notes
+    pub quantize: Option<u32>,
+    /// Rescale event times to this many ticks per quarter note, or keep the MIDI file's own resolution if `None`
+    pub ppq_normalize: Option<u16>,
+    /// Only convert these track indices, or all tracks if `None`
+    pub tracks: Option<Vec<usize>>,
+    /// Track indices to leave out of the conversion
+    pub exclude_tracks: Vec<usize>,
+    /// Only take tempo and time signature events from this track index, or from all tracks if `None`
+    pub tempo_track: Option<usize>,
+    /// Moves commands from one MIDI channel to another channel on the player, unmapped channels stay the same
+    pub channel_map: HashMap<u8, u8>,
+    /// Gain applied to the volume of MIDI channels, results are clamped to 0-127
+    pub channel_volume_scale: HashMap<u8, f32>,
+    /// Set a default volume, panning and instrument on channels that play notes without setting those first
+    pub init_channels: bool,
+    /// Drum kit program to select on channel 9 if it plays notes before a program change, or `None` to leave it to the player
+    pub default_drum_kit: Option<u8>,
+    /// MIDI channels to drop pitch bends on
+    pub no_bend_channels: Vec<u8>,
+    /// MIDI channels that only play one note at a time, a new note releases the one that's playing
+    pub mono_channels: Vec<u8>,
+    /// Groups of MIDI channels to fold onto fewer player channels
+    pub downmix: Vec<DownmixGroup>,
+    /// Which player channel of a downmix group a new note goes to
+    pub downmix_policy: DownmixPolicy,
+    /// Longest a single wait command may wait, longer gaps are split over several waits so the player reads a command at least this often
+    pub max_wait_ticks: Option<u32>,
+    /// Wait lengths the player's WaitTicks opcodes stand for
+    pub wait_lut: WaitLut,
+    /// Raw tempo value per second of tick length, only needs changing for players with a modified timer
+    pub tick_length_multiplier: f64,
+    /// Replace a ReleaseNote directly followed by a PlayNote of the same key with a single RetriggerNote
+    pub retrigger_notes: bool,
+    /// Keep the velocity of NoteOff events, using ReleaseNoteVelocity instead of ReleaseNote
+    pub release_velocity: bool,
+    /// Log the emitted wait commands for every MIDI event time, to debug timing issues
+    pub timeline: bool,
+    /// Only convert the part of the song from this point on, state set before it is carried over to the start
+    pub start: Option<TimePoint>,
+    /// Only convert the part of the song before this point, notes still playing there are released
+    pub end: Option<TimePoint>,
+    /// Start the song at its first note, everything before it happens at tick 0 instead
+    pub trim_leading_silence: bool,
+    /// Restore the channel state and tempo from the loop start right after it, so every loop iteration sounds the same
+    pub loop_snapshot: bool,
+    /// Write out the loop this many times instead of jumping back to the loop start, for players that can't loop forever
+    pub loop_count: Option<u32>,
+    /// Fail the conversion on unsupported events (text meta events excluded), channels out of range and tempos out of range
+    pub strict: bool,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            loop_song: false,
+            max_channels: MAX_CHANNELS,
+            velocity_curve: VelocityCurve::Linear,
+            pan_law: PanLaw::Linear,
+            instrument_map: None,
+            drop_unmapped_drums: false,
+            split_drums: None,
+            transpose: 0,
+            transpose_drums: false,
+            note_range: None,
+            drop_out_of_range_notes: false,
+            tempo_bpm: None,
+            default_tempo_bpm: 120.0,
+            release_hanging_notes: false,
+            trim_tail: false,
+            overlap: None,
+            quantize: None,
+            ppq_normalize: None,
+            tracks: None,
+            exclude_tracks: Vec::new(),
+            tempo_track: None,
+            channel_map: HashMap::new(),
+            channel_volume_scale: HashMap::new(),
+            init_channels: false,
+            default_drum_kit: Some(0),
+            no_bend_channels: Vec::new(),
+            mono_channels: Vec::new(),
+            downmix: Vec::new(),
+            downmix_policy: DownmixPolicy::LastNote,
+            max_wait_ticks: None,
+            wait_lut: WaitLut::default(),
+            tick_length_multiplier: TICK_LENGTH_MULTIPLIER,
+            start: None,
+            end: None,
+            trim_leading_silence: false,
+            loop_snapshot: false,
+            loop_count: None,
+            strict: false,
+            retrigger_notes: false,
+            release_velocity: false,
+            timeline: false,
+        }
+    }
+}
+
+/// Time division of the converted song, which is the MIDI file's own unless it's rescaled to a different PPQ
+fn output_timing(smf: &Smf, options: &ConvertOptions) -> midly::Timing {
+    match (options.ppq_normalize, smf.header.timing) {
+        (Some(target), midly::Timing::Metrical(_)) => midly::Timing::Metrical(midly::num::u15::new(target)),
+        (_, timing) => timing,
+    }
+}
+
+/// Moves an absolute time in the MIDI file's ticks to the output time division, rounded to the nearest tick
+fn rescale_time(time: u32, smf: &Smf, options: &ConvertOptions) -> u32 {
+    match (smf.header.timing, output_timing(smf, options)) {
+        (midly::Timing::Metrical(from), midly::Timing::Metrical(to)) if from != to => {
+            let (from, to) = (from.as_int().max(1) as u64, to.as_int() as u64);
+            ((time as u64 * to + from / 2) / from) as u32
+        }
+        _ => time,
+    }
+}
+
+/// Events from all tracks, along with their absolute time in ticks
+type TimedEvents<'a> = Vec<(u32, TrackEventKind<'a>)>;
+
+/// Reads the tracks that are part of the conversion and squashes them into one list of events, sorted by time.
+/// Also returns where the window ends relative to its start, if there's an end set
+fn merge_tracks<'a>(smf: &Smf<'a>, options: &ConvertOptions) -> Result<(TimedEvents<'a>, Option<u32>), ConvertError> {
+    if options.ppq_normalize.is_some() && matches!(smf.header.timing, midly::Timing::Timecode(..)) {
+        warn!("Ignoring PPQ normalization, time division is SMPTE timecode");
+    }
+
+    // Quantization grid size in ticks, a whole note is 4 quarter notes
+    let quantize_grid = match (options.quantize, output_timing(smf, options)) {
+        (Some(division), midly::Timing::Metrical(ticks_per_quarter_note)) => Some((ticks_per_quarter_note.as_int() as u32 * 4 / division.max(1)).max(1)),
+        (Some(_), midly::Timing::Timecode(..)) => {
+            warn!("Ignoring quantization, time division is SMPTE timecode");
+            None
+        }
+        (None, _) => None,
+    };
+
+    // Read all the tracks and events, and squash them together into one track
+    let mut timed_events = Vec::new();
+
+    for &track_index in options.tracks.iter().flatten().chain(&options.exclude_tracks).chain(&options.tempo_track) {
+        if track_index >= smf.tracks.len() {
+            warn!("Track {track_index} does not exist, the MIDI file only has {} tracks", smf.tracks.len());
+        }
+    }
+
+    // In format 2 every track is its own sequence, so instead of playing them at the same time,
+    // play them one after another, each one in its own section
+    let sequential = smf.header.format == midly::Format::Sequential;
+    if sequential {
+        info!("MIDI file contains {} independent sequences, converting each track to its own section", smf.tracks.len());
+    }
+    let mut track_start_time = 0;
+    let mut first_sequence = true;
+
+    let names = track_names(smf);
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let track_label = match names.get(&track_index) {
+            Some(name) => format!("{track_index} \"{name}\""),
+            None => track_index.to_string(),
+        };
+        if !track_included(track_index, options) {
+            debug!("Skipping track {track_label}");
+            continue;
+        }
+        debug!("Track {track_label} has {} events", track.len());
+
+        let mut time = 0;
+        if sequential {
+            time = track_start_time;
+            // The first sequence starts in the first section along with the setup at the start of the song
+            if !first_sequence {
+                timed_events.push((rescale_time(time, smf, options), TrackEventKind::Meta(midly::MetaMessage::Marker(b"section"))));
+            }
+            first_sequence = false;
+        }
+        for event in track {
+            time += event.delta.as_int();
+            if options.tempo_track.is_some_and(|tempo_track| tempo_track != track_index)
+                && matches!(event.kind, TrackEventKind::Meta(midly::MetaMessage::Tempo(_) | midly::MetaMessage::TimeSignature(..))) {
+                debug!("Ignoring {:?} on track {track_index}, it's not the tempo track", event.kind);
+                continue;
+            }
+            // Rescale and snap the absolute time rather than the delta, so rounding errors don't add up
+            let event_time = rescale_time(time, smf, options);
+            let event_time = match quantize_grid {
+                Some(grid) => (event_time + grid / 2) / grid * grid,
+                None => event_time,
+            };
+            timed_events.push((event_time, event.kind));
+        }
+        track_start_time = time;
+    }
+
+    for (channel, tracks) in channel_conflicts(smf, options) {
+        let tracks: Vec<String> = tracks.iter().map(usize::to_string).collect();
+        warn!("Tracks {} set conflicting state on channel {channel}, the latest change wins", tracks.join(", "));
+    }
+
+    // Stable, so events that happen at the same time stay in track order until they're put in play order
+    timed_events.sort_by_key(|&(time, _)| time);
+    order_same_tick_events(&mut timed_events);
+
+    // Cut out the requested part of the song, and move it so it starts at tick 0
+    // Positions in ticks are in the MIDI file's time division, so they're rescaled along with the events
+    let window_start = match options.start.map(|start| start.to_ticks(smf.header.timing)).transpose() {
+        Ok(start) => start.map_or(0, |start| rescale_time(start, smf, options)),
+        Err(err) => return Err(ConvertError::UnsupportedTiming(format!("Can't use start time, {err}"))),
+    };
+    let mut window_end = match options.end.map(|end| end.to_ticks(smf.header.timing)).transpose() {
+        Ok(end) => end.map(|end| rescale_time(end, smf, options).saturating_sub(window_start)),
+        Err(err) => return Err(ConvertError::UnsupportedTiming(format!("Can't use end time, {err}"))),
+    };
+    if window_start > 0 {
+        // Events that set up state still matter after the start, so the latest one of each kind happens at tick 0 instead
+        let split = timed_events.partition_point(|&(time, _)| time < window_start);
+        let mut seen_state = HashSet::new();
+        let mut carried_events: Vec<_> = timed_events[..split].iter().rev()
+            .filter(|(_, event)| carries_state(event) && state_key(event).is_none_or(|key| seen_state.insert(key)))
+            .map(|&(_, event)| (0, event))
+            .collect();
+        carried_events.reverse();
+        let kept_events = timed_events[split..].iter().map(|&(time, event)| (time - window_start, event));
+        timed_events = carried_events.into_iter().chain(kept_events).collect();
+    }
+    if let Some(window_end) = window_end {
+        timed_events.retain(|&(time, _)| time < window_end);
+    }
+    if options.trim_leading_silence {
+        // Unlike the start of the window, every event is kept, the setup just happens without waiting first
+        if let Some(&(first_note, _)) = timed_events.iter().find(|(_, event)| is_note_on(event)) {
+            debug!("Trimming {first_note} ticks of silence before the first note");
+            for (time, _) in timed_events.iter_mut() {
+                *time = time.saturating_sub(first_note);
+            }
+            window_end = window_end.map(|end| end - first_note);
+        }
+    }
+
+    Ok((timed_events, window_end))
+}
+
+/// Puts events that happen at the same time in the order they should play in, keeping track order otherwise.
+/// Notes held from before are released first, they belong to what came before, not to a loop or section starting here.
+/// Then setup like program changes and controllers so new notes use the right instrument, volume, etc.
+/// Then new notes start, and last, notes that start and end on this tick are released again,
+/// so a note that's zero ticks long (or quantized down to that) doesn't keep playing forever
+fn order_same_tick_events(timed_events: &mut TimedEvents) {
+    const HELD_NOTE_OFF: u8 = 0;
+    const SETUP: u8 = 1;
+    const NOTE_ON: u8 = 2;
+    const SAME_TICK_NOTE_OFF: u8 = 3;
+
+    // Number of NoteOns from earlier ticks that weren't released yet, for each channel and key
+    let mut held_notes: HashMap<(u8, u8), u32> = HashMap::new();
+    for events in timed_events.chunk_by_mut(|(time_a, _), (time_b, _)| time_a == time_b) {
+        let mut started_notes: HashMap<(u8, u8), u32> = HashMap::new();
+        for (_, event) in events.iter() {
+            if let Some(note) = note_on_key(event) {
+                *started_notes.entry(note).or_default() += 1;
+            }
+        }
+        let mut classes = Vec::with_capacity(events.len());
+        for (_, event) in events.iter() {
+            let class = if let Some(note) = note_off_key(event) {
+                match (held_notes.get_mut(&note), started_notes.get_mut(&note)) {
+                    (Some(held), _) if *held > 0 => {
+                        *held -= 1;
+                        HELD_NOTE_OFF
+                    }
+                    (_, Some(started)) if *started > 0 => {
+                        *started -= 1;
+                        SAME_TICK_NOTE_OFF
+                    }
+                    // Nothing to release, so it doesn't matter where it goes
+                    _ => HELD_NOTE_OFF,
+                }
+            } else if note_on_key(event).is_some() {
+                NOTE_ON
+            } else {
+                SETUP
+            };
+            classes.push(class);
+        }
+        for (note, count) in started_notes {
+            *held_notes.entry(note).or_default() += count;
+        }
+
+        let mut ordered: Vec<_> = classes.into_iter().zip(events.iter().copied()).collect();
+        ordered.sort_by_key(|&(class, _)| class);
+        for (slot, (_, event)) in events.iter_mut().zip(ordered) {
+            *slot = event;
+        }
+    }
+}
+
+/// Lists the merged events that the conversion works from, one per line along with their tick position,
+/// to tell apart problems with merging the tracks from problems with converting the events
+pub fn dump_events(smf: &Smf, options: &ConvertOptions) -> Result<Vec<String>, ConvertError> {
+    let (timed_events, _) = merge_tracks(smf, options)?;
+    Ok(timed_events.iter().map(|(time, event)| format!("tick {time:>8}  {}", describe_event(event))).collect())
+}
+
+/// Readable one line description of an event, with text as text instead of bytes
+fn describe_event(event: &TrackEventKind) -> String {
+    use midly::{MetaMessage, MidiMessage};
+    match *event {
+        TrackEventKind::Midi { channel, message } => {
+            let message = match message {
+                MidiMessage::NoteOn { key, vel } => format!("NoteOn key {key} velocity {vel}"),
+                MidiMessage::NoteOff { key, vel } => format!("NoteOff key {key} velocity {vel}"),
+                MidiMessage::Aftertouch { key, vel } => format!("Aftertouch key {key} pressure {vel}"),
+                MidiMessage::Controller { controller, value } => format!("Controller {controller} value {value}"),
+                MidiMessage::ProgramChange { program } => format!("ProgramChange {program}"),
+                MidiMessage::ChannelAftertouch { vel } => format!("ChannelAftertouch {vel}"),
+                MidiMessage::PitchBend { bend } => format!("PitchBend {}", bend.as_int()),
+            };
+            format!("channel {channel:>2}  {message}")
+        }
+        TrackEventKind::Meta(message) => {
+            let (name, text) = match message {
+                MetaMessage::Text(text) => ("Text", text),
+                MetaMessage::Copyright(text) => ("Copyright", text),
+                MetaMessage::TrackName(text) => ("TrackName", text),
+                MetaMessage::InstrumentName(text) => ("InstrumentName", text),
+                MetaMessage::Lyric(text) => ("Lyric", text),
+                MetaMessage::Marker(text) => ("Marker", text),
+                MetaMessage::CuePoint(text) => ("CuePoint", text),
+                MetaMessage::ProgramName(text) => ("ProgramName", text),
+                MetaMessage::DeviceName(text) => ("DeviceName", text),
+                MetaMessage::Tempo(tempo) => return format!("meta        Tempo {tempo} microseconds per quarter note"),
+                _ => return format!("meta        {message:?}"),
+            };
+            format!("meta        {name} \"{}\"", String::from_utf8_lossy(text))
+        }
+        _ => format!("{event:?}"),
+    }
+}
+
+/// Converts a parsed MIDI file into a stream of FlanSeq commands.
+/// In strict mode this fails if any events or channels had to be dropped, after logging each of them.
+pub fn convert_smf(smf: &Smf, options: &ConvertOptions) -> Result<Vec<FlanSeqCommand>, ConvertError> {
+    debug!("Using tick length multiplier {}", options.tick_length_multiplier);
+    let (mut timed_events, window_end) = merge_tracks(smf, options)?;
+
+    // The last end of track is the length of the song, which can be well after the last note when the song ends in silence.
+    // It's taken out here so the tail can still be trimmed, the wait up to it is added at the end
+    let song_end = timed_events.iter()
+        .filter(|(_, event)| is_end_of_track(event))
+        .map(|&(time, _)| time)
+        .max();
+    timed_events.retain(|(_, event)| !is_end_of_track(event));
+
+    // Now let's convert it into FlanSeqCommands
+    let mut fdss_commands: Vec<FlanSeqCommand> = Vec::new();
+    let mut prev_time = 0;
+    let mut emitted_ticks = 0u64;
+    let mut pitch_bend_range_coarse = [2; 16];
+    let mut pitch_bend_range_fine = [0; 16];
+
+    // Pitch bend and fine tuning (RPN 1) both end up in SetChannelPitch, in 10ths of cents
+    let mut pitch_bend = [0.0f32; 16];
+    let mut fine_tuning = [0.0f32; 16];
+
+    // RPN/NRPN selection persists until the next CC98-CC101, data entry may come in at a later tick
+    let mut cc100 = [-1; 16];
+    let mut cc101 = [-1; 16];
+    let mut cc98 = [-1; 16];
+    let mut cc99 = [-1; 16];
+    let mut nrpn_selected = [false; 16];
+    let mut data_entry_coarse = [0u16; 16];
+
+    // Keys that are currently playing on each channel
+    let mut active_notes: [BTreeSet<u8>; 16] = Default::default();
+
+    // Number of NoteOns without a matching NoteOff yet for each key, this can be more than 1 when notes on the same key overlap
+    let mut held_note_count = [[0u32; 128]; 16];
+
+    // Bank selected with CC0 (MSB) and CC32 (LSB), applied on the next program change
+    let mut bank_select = [0u16; 16];
+
+    // CC7 and CC11 both contribute to the final channel volume, as 14-bit values with CC39 and CC43 as the LSB
+    let mut channel_volume = [100u16 << 7; 16];
+    let mut channel_expression = [127u16 << 7; 16];
+
+    // CC65 and CC5 are sent to the player together, so a change to either one needs the other's last value
+    let mut portamento_on = [false; 16];
+    let mut portamento_time = [0u8; 16];
+
+    // Loop points can come from both markers and CC111, make sure we only emit them once
+    let mut loop_start_emitted = false;
+    let mut loop_end_emitted = false;
+
+    // Everything the player can't represent, so the user can be told what got lost
+    let mut dropped_events = DroppedEvents::default();
+    let mut notes_out_of_range = 0;
+    let mut strict_violations = 0;
+
+    // In format 2 every track is a sequence of its own, and the player starts each one from scratch
+    let sequential = smf.header.format == midly::Format::Sequential;
+
+    // With SMPTE timecode the tick length is fixed by the frame rate, so set the tempo once up front
+    // The same goes for a fixed tempo override
+    if options.tempo_bpm.is_some() && matches!(output_timing(smf, options), midly::Timing::Timecode(..)) {
+        warn!("Ignoring tempo override, time division is SMPTE timecode");
+    }
+    // Tempo to start the song with, or each sequence in format 2, unless it sets one itself right at its start
+    let start_tempo = |start_time: u32| -> Result<Option<FlanSeqCommand>, ConvertError> {
+        let tempo = match (output_timing(smf, options), options.tempo_bpm) {
+            (midly::Timing::Timecode(fps, subframes_per_frame), _) => {
+                let seconds_per_tick = 1.0 / (fps.as_f32() as f64 * subframes_per_frame as f64);
+                clamp_tempo(tempo_from_seconds_per_tick(seconds_per_tick, options.tick_length_multiplier), options, || {
+                    format!("Timecode tick length of {seconds_per_tick} seconds can't be represented")
+                })?
+            }
+            (midly::Timing::Metrical(ticks_per_quarter_note), Some(tempo_bpm)) => tempo_from_bpm(tempo_bpm, ticks_per_quarter_note.as_int() as f64, options)?,
+            // Otherwise the player would keep the tempo of whatever it played before
+            (midly::Timing::Metrical(ticks_per_quarter_note), None) => {
+                let starts_with_tempo = timed_events.iter()
+                    .skip_while(|&&(time, _)| time < start_time)
+                    .take_while(|&&(time, _)| time == start_time)
+                    .any(|(_, event)| matches!(event, TrackEventKind::Meta(midly::MetaMessage::Tempo(_))));
+                if starts_with_tempo {
+                    return Ok(None);
+                }
+                debug!("No tempo set at tick {start_time}, using {} BPM", options.default_tempo_bpm);
+                tempo_from_bpm(options.default_tempo_bpm, ticks_per_quarter_note.as_int() as f64, options)?
+            }
+        };
+        Ok(Some(FlanSeqCommand::SetTempo { tempo }))
+    };
+    fdss_commands.extend(start_tempo(0)?);
+
+    for events in timed_events.chunk_by(|(time_a, _), (time_b, _)| time_a == time_b) {
+        let time = events[0].0;
+        if prev_time != time {
+            let delta_time = time - prev_time;
+            let first_wait = fdss_commands.len();
+            push_wait_ticks(&mut fdss_commands, delta_time, options.max_wait_ticks, &options.wait_lut);
+            let waits = &fdss_commands[first_wait..];
+            emitted_ticks += waits.iter().map(|command| command.wait_ticks_with(&options.wait_lut.0) as u64).sum::<u64>();
+            if options.timeline {
+                let wait_list: Vec<String> = waits.iter().map(|command| match *command {
+                    FlanSeqCommand::WaitTicks { index_into_lut } => format!("LUT[{index_into_lut}]={}", options.wait_lut.0[index_into_lut]),
+                    _ => format!("long {}", command.wait_ticks()),
+                }).collect();
+                info!("MIDI tick {time:>8}: delta {delta_time:>6}, emitted tick {emitted_ticks:>8}, waits [{}]", wait_list.join(", "));
+            }
+        }
+        prev_time = time;
+        for &(_, event) in events {
+            match event {
+                TrackEventKind::Midi {channel, message} => {
+                    match message {
+                        midly::MidiMessage::NoteOn{key, vel} if vel.as_int() > 0 => {
+                            let Some(key) = map_key_unranged(channel.into(), key.into(), options) else { continue };
+                            if outside_note_range(channel.into(), key, options) {
+                                notes_out_of_range += 1;
+                            }
+                            let Some(key) = fit_note_range(channel.into(), key, options) else { continue };
+                            let ch = channel.as_int() as usize;
+                            let overlapping = active_notes[ch].contains(&key);
+                            held_note_count[ch][key as usize] += 1;
+                            // Last note priority, same key overlaps are still up to the overlap policy
+                            if options.mono_channels.contains(&(ch as u8)) {
+                                let other_keys: Vec<u8> = active_notes[ch].iter().copied().filter(|&active_key| active_key != key).collect();
+                                for other_key in other_keys {
+                                    active_notes[ch].remove(&other_key);
+                                    fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel.into(), key: other_key });
+                                }
+                            }
+                            match options.overlap {
+                                Some(OverlapPolicy::Retrigger) if overlapping => fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel.into(), key }),
+                                Some(OverlapPolicy::Merge | OverlapPolicy::Ignore) if overlapping => {
+                                    debug!("Dropping note {key} on channel {ch}, it's already playing");
+                                    continue;
+                                }
+                                _ => {}
+                            }
+                            active_notes[ch].insert(key);
+                            fdss_commands.push(FlanSeqCommand::PlayNote { channel: channel.into(), key, velocity: map_velocity(vel.into(), options.velocity_curve) })
+                        },
+                        // A NoteOn with velocity 0 is a NoteOff as far as the MIDI spec is concerned, it just doesn't have a release velocity
+                        midly::MidiMessage::NoteOn{key, ..} | midly::MidiMessage::NoteOff{key, ..} => {
+                            let Some(key) = map_key(channel.into(), key.into(), options) else { continue };
+                            let ch = channel.as_int() as usize;
+                            let held_notes = &mut held_note_count[ch][key as usize];
+                            *held_notes = held_notes.saturating_sub(1);
+                            let release = match options.overlap {
+                                Some(OverlapPolicy::Retrigger | OverlapPolicy::Merge) => *held_notes == 0,
+                                Some(OverlapPolicy::Ignore) => active_notes[ch].contains(&key),
+                                None => true,
+                            };
+                            // On mono channels the note might have been cut off by a newer one already
+                            let release = release && (active_notes[ch].contains(&key) || !options.mono_channels.contains(&(ch as u8)));
+                            if !release {
+                                debug!("Not releasing note {key} on channel {ch} yet, an overlapping note is still held");
+                                continue;
+                            }
+                            active_notes[ch].remove(&key);
+                            match message {
+                                midly::MidiMessage::NoteOff { vel, .. } if options.release_velocity => {
+                                    fdss_commands.push(FlanSeqCommand::ReleaseNoteVelocity { channel: channel.into(), key, velocity: vel.into() })
+                                }
+                                _ => fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel.into(), key }),
+                            }
+                        },
+                        midly::MidiMessage::ProgramChange{program} => {
+                            let channel = u8::from(channel);
+                            let program = u8::from(program);
+                            let bank = bank_select[channel as usize];
+                            if bank != 0 {
+                                debug!("Program {program} on channel {channel} selected from bank {bank} (MSB {}, LSB {})", bank >> 7, bank & 0x7F);
+                            }
+                            let index = instrument_index(channel, bank, program, options);
+                            fdss_commands.push(FlanSeqCommand::SetChannelInstrument { channel, index })
+                        },
+                        midly::MidiMessage::PitchBend {bend} => {
+                            let ch = channel.as_int() as usize;
+                            if options.no_bend_channels.contains(&(ch as u8)) {
+                                debug!("Dropping pitch bend on channel {ch}, pitch bends are disabled for it");
+                                continue;
+                            }
+                            let pitch_bend_range_cents = (pitch_bend_range_coarse[ch] as f32 * 100.0) + (pitch_bend_range_fine[ch] as f32 * 1.0);
+                            let bend_value_normalized = bend.as_f32();
+                            pitch_bend[ch] = (pitch_bend_range_cents * 10.0) * bend_value_normalized;
+                            let pitch = channel_pitch(ch as u8, pitch_bend[ch] + fine_tuning[ch]);
+                            fdss_commands.push(FlanSeqCommand::SetChannelPitch { channel: channel.into(), pitch })
+                        },
+                        // Keys go through the same mapping as notes, so the pressure ends up on the note that's playing
+                        midly::MidiMessage::Aftertouch{key, vel} => {
+                            let Some(key) = map_key(channel.into(), key.into(), options) else { continue };
+                            fdss_commands.push(FlanSeqCommand::SetNoteAftertouch { channel: channel.into(), key, pressure: vel.into() })
+                        }
+                        midly::MidiMessage::ChannelAftertouch{vel} => fdss_commands.push(FlanSeqCommand::SetChannelAftertouch { channel: channel.into(), pressure: vel.into() }),
+                        midly::MidiMessage::Controller{controller, value} => match u8::from(controller) {
+                            0 => {
+                                let bank = &mut bank_select[channel.as_int() as usize];
+                                *bank = (u16::from(u8::from(value)) << 7) | (*bank & 0x7F);
+                            }
+                            32 => {
+                                let bank = &mut bank_select[channel.as_int() as usize];
+                                *bank = (*bank & !0x7F) | u16::from(u8::from(value));
+                            }
+                            1 => fdss_commands.push(FlanSeqCommand::SetChannelModulation { channel: channel.into(), depth: value.into() }),
+                            // Expression is folded into the channel volume here, rather than giving the player another command to deal with.
+                            // A new MSB clears the LSB, files that only send the MSB get the same volume as before
+                            7 | 11 | 39 | 43 => {
+                                let ch = channel.as_int() as usize;
+                                let value = u16::from(u8::from(value));
+                                match u8::from(controller) {
+                                    7 => channel_volume[ch] = value << 7,
+                                    11 => channel_expression[ch] = value << 7,
+                                    39 => channel_volume[ch] = (channel_volume[ch] & !0x7F) | value,
+                                    _ => channel_expression[ch] = (channel_expression[ch] & !0x7F) | value,
+                                }
+                                let volume = effective_volume(ch as u8, channel_volume[ch], channel_expression[ch], options);
+                                fdss_commands.push(FlanSeqCommand::SetChannelVolume { channel: channel.into(), volume })
+                            }
+                            5 | 65 => {
+                                let ch = channel.as_int() as usize;
+                                if u8::from(controller) == 65 {
+                                    portamento_on[ch] = u8::from(value) >= 64;
+                                } else {
+                                    portamento_time[ch] = value.into();
+                                }
+                                fdss_commands.push(FlanSeqCommand::SetChannelPortamento { channel: channel.into(), on: portamento_on[ch], time: portamento_time[ch] })
+                            }
+                            // The source key goes through the same drum mapping and transposition as notes, so it matches the keys the player sees
+                            84 => {
+                                let Some(key) = map_key(channel.into(), value.into(), options) else { continue };
+                                fdss_commands.push(FlanSeqCommand::SetChannelPortamentoSource { channel: channel.into(), key })
+                            }
+                            10 => fdss_commands.push(FlanSeqCommand::SetChannelPanning { channel: channel.into(), panning: map_pan(value.into(), options.pan_law) }),
+                            64 => fdss_commands.push(FlanSeqCommand::SetChannelSustain { channel: channel.into(), on: u8::from(value) >= 64 }),
+                            66 => fdss_commands.push(FlanSeqCommand::SetChannelSostenuto { channel: channel.into(), on: u8::from(value) >= 64 }),
+                            67 => fdss_commands.push(FlanSeqCommand::SetChannelSoftPedal { channel: channel.into(), on: u8::from(value) >= 64 }),
+                            98 => {
+                                cc98[channel.as_int() as usize] = u8::from(value) as i32;
+                                nrpn_selected[channel.as_int() as usize] = true;
+                            }
+                            99 => {
+                                cc99[channel.as_int() as usize] = u8::from(value) as i32;
+                                nrpn_selected[channel.as_int() as usize] = true;
+                            }
+                            100 => {
+                                cc100[channel.as_int() as usize] = u8::from(value) as i32;
+                                nrpn_selected[channel.as_int() as usize] = false;
+                            }
+                            101 => {
+                                cc101[channel.as_int() as usize] = u8::from(value) as i32;
+                                nrpn_selected[channel.as_int() as usize] = false;
+                            }
+                            6 => {
+                                let ch = channel.as_int() as usize;
+                                if nrpn_selected[ch] {
+                                    data_entry_coarse[ch] = u16::from(u8::from(value)) << 7;
+                                    let param = ((cc99[ch].max(0) as u16) << 7) | cc98[ch].max(0) as u16;
+                                    debug!("NRPN {param} on channel {ch} set to {}", data_entry_coarse[ch]);
+                                    fdss_commands.push(FlanSeqCommand::SetChannelParam { channel: ch as u8, param, value: data_entry_coarse[ch] })
+                                } else if cc100[ch] == 0 && cc101[ch] == 0 {
+                                    pitch_bend_range_coarse[ch] = value.into()
+                                } else if cc100[ch] == 1 && cc101[ch] == 0 {
+                                    data_entry_coarse[ch] = u16::from(u8::from(value)) << 7;
+                                    fine_tuning[ch] = fine_tuning_from_rpn(data_entry_coarse[ch]);
+                                    let pitch = channel_pitch(ch as u8, pitch_bend[ch] + fine_tuning[ch]);
+                                    fdss_commands.push(FlanSeqCommand::SetChannelPitch { channel: ch as u8, pitch })
+                                } else if cc100[ch] == 2 && cc101[ch] == 0 {
+                                    // Coarse tuning only uses the MSB, 64 is no transposition
+                                    let semitones = u8::from(value) as i8 - 64;
+                                    fdss_commands.push(FlanSeqCommand::SetChannelTranspose { channel: ch as u8, semitones })
+                                }
+                            }
+                            38 => {
+                                let ch = channel.as_int() as usize;
+                                if nrpn_selected[ch] {
+                                    let param = ((cc99[ch].max(0) as u16) << 7) | cc98[ch].max(0) as u16;
+                                    let param_value = data_entry_coarse[ch] | u16::from(u8::from(value));
+                                    debug!("NRPN {param} on channel {ch} set to {param_value}");
+                                    fdss_commands.push(FlanSeqCommand::SetChannelParam { channel: ch as u8, param, value: param_value })
+                                } else if cc100[ch] == 0 && cc101[ch] == 0 {
+                                    pitch_bend_range_fine[ch] = value.into()
+                                } else if cc100[ch] == 1 && cc101[ch] == 0 {
+                                    fine_tuning[ch] = fine_tuning_from_rpn(data_entry_coarse[ch] | u16::from(u8::from(value)));
+                                    let pitch = channel_pitch(ch as u8, pitch_bend[ch] + fine_tuning[ch]);
+                                    fdss_commands.push(FlanSeqCommand::SetChannelPitch { channel: ch as u8, pitch })
+                                }
+                            }
+                            // All Sound Off and All Notes Off
+                            120 | 123 => {
+                                held_note_count[channel.as_int() as usize] = [0; 128];
+                                for key in std::mem::take(&mut active_notes[channel.as_int() as usize]) {
+                                    fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel.into(), key });
+                                }
+                            }
+                            111 => {
+                                if !loop_start_emitted {
+                                    fdss_commands.push(FlanSeqCommand::SetLoopStart);
+                                    loop_start_emitted = true;
+                                }
+                            }
+                            _ => {
+                                report_unsupported(&format!("controller {controller}, value {value}"), time, options, &mut strict_violations);
+                                dropped_events.add_controller(controller.into());
+                            }
+                        }
+                    }
+                },
+                TrackEventKind::Meta(message) => {
+                    match message {
+                        midly::MetaMessage::Tempo(tempo) => {
+                            if options.tempo_bpm.is_some() {
+                                debug!("Ignoring tempo change, tempo is overridden");
+                                continue;
+                            }
+                            let ticks_per_quarter_note = match output_timing(smf, options) {
+                                midly::Timing::Metrical(ticks_per_quarter_note) => ticks_per_quarter_note.as_int() as f64,
+                                midly::Timing::Timecode(..) => {
+                                    debug!("Ignoring tempo change, time division is SMPTE timecode");
+                                    continue;
+                                }
+                            };
+                            let microseconds_per_quarter_note = tempo.as_int() as f64;
+                            let tempo_bpm = 60_000_000.0 / microseconds_per_quarter_note;
+                            fdss_commands.push(FlanSeqCommand::SetTempo { tempo: tempo_from_bpm(tempo_bpm, ticks_per_quarter_note, options)? })
+                        },
+                        midly::MetaMessage::TimeSignature(num, denom, _ticks_per_click, _note32_per_midi_quarter) => {
+                            // The denominator is stored as a power of two, anything past 2^7 doesn't fit in a byte
+                            if denom > 7 {
+                                warn!("Time signature {num}/2^{denom} is out of range, using {num}/128 instead");
+                            }
+                            fdss_commands.push(FlanSeqCommand::SetTimeSignature { numerator: num, denominator: 1 << denom.min(7) })
+                        },
+                        midly::MetaMessage::Marker(text) => {
+                            let text = String::from_utf8_lossy(text);
+                            match text.trim().to_lowercase().as_str() {
+                                "loopstart" => {
+                                    if !loop_start_emitted {
+                                        fdss_commands.push(FlanSeqCommand::SetLoopStart);
+                                        loop_start_emitted = true;
+                                    }
+                                },
+                                "loopend" => {
+                                    if !loop_end_emitted {
+                                        fdss_commands.push(FlanSeqCommand::JumpToLoopStart);
+                                        loop_end_emitted = true;
+                                    }
+                                },
+                                "section" => {
+                                    fdss_commands.push(FlanSeqCommand::SectionStart);
+                                    if sequential {
+                                        fdss_commands.extend(start_tempo(time)?);
+                                    }
+                                }
+                                _ => match marker_id(&text, options) {
+                                    Some(id) => fdss_commands.push(FlanSeqCommand::Marker { id }),
+                                    None => {
+                                        debug!("Unsupported marker \"{text}\"");
+                                        dropped_events.add_meta_event(&message);
+                                    }
+                                },
+                            }
+                        },
+                        // Already taken out to find the length of the song
+                        midly::MetaMessage::EndOfTrack => {}
+                        // Track names were already picked up while merging the tracks
+                        midly::MetaMessage::TrackName(_) => {}
+                        midly::MetaMessage::InstrumentName(name) => debug!("Instrument name \"{}\"", String::from_utf8_lossy(name)),
+                        midly::MetaMessage::CuePoint(text) => match marker_id(&String::from_utf8_lossy(text), options) {
+                            Some(id) => fdss_commands.push(FlanSeqCommand::Marker { id }),
+                            None => {
+                                debug!("Unsupported meta event {message:?}");
+                                dropped_events.add_meta_event(&message);
+                            }
+                        },
+                        // Text doesn't change how the song plays, so it's not worth failing strict mode over
+                        midly::MetaMessage::Text(_) | midly::MetaMessage::Copyright(_) | midly::MetaMessage::Lyric(_)
+                        | midly::MetaMessage::ProgramName(_) | midly::MetaMessage::DeviceName(_) => {
+                            debug!("Unsupported meta event {message:?}");
+                            dropped_events.add_meta_event(&message);
+                        }
+                        _ => {
+                            report_unsupported(&format!("meta event {message:?}"), time, options, &mut strict_violations);
+                            dropped_events.add_meta_event(&message);
+                        }
+                    }
+                },
+                TrackEventKind::SysEx(data) => match master_volume(data) {
+                    Some(volume) => fdss_commands.push(FlanSeqCommand::SetMasterVolume { volume }),
+                    None => {
+                        report_unsupported(&format!("SysEx message {data:02X?}"), time, options, &mut strict_violations);
+                        dropped_events.add_other_event(&event);
+                    }
+                },
+                _ => {
+                    report_unsupported(&format!("event {event:?}"), time, options, &mut strict_violations);
+                    dropped_events.add_other_event(&event);
+                }
+            }
+        }
+    }
+
+    for line in dropped_events.to_string().lines() {
+        info!("{line}");
+    }
+    if let Some(range) = options.note_range.filter(|_| notes_out_of_range > 0) {
+        let action = if options.drop_out_of_range_notes { "Dropped" } else { "Clamped" };
+        info!("{action} {notes_out_of_range} notes outside the note range {}-{}", range.low, range.high);
+    }
+    if strict_violations > 0 {
+        return Err(ConvertError::UnsupportedEvents(strict_violations));
+    }
+
+    // Keep the full length of the window or the song, even if the last event is earlier
+    let end_time = window_end.or(song_end.filter(|_| !options.trim_tail));
+    if let Some(end_time) = end_time.filter(|&end_time| end_time > prev_time) {
+        debug!("Waiting {} ticks after the last event until the end of the song", end_time - prev_time);
+        push_wait_ticks(&mut fdss_commands, end_time - prev_time, options.max_wait_ticks, &options.wait_lut);
+    }
+
+    // Make sure nothing keeps playing forever, or into the next loop iteration.
+    // Notes cut off by the end of the window would otherwise never be released either
+    if options.release_hanging_notes || window_end.is_some() {
+        for (channel, keys) in active_notes.iter().enumerate() {
+            for &key in keys {
+                debug!("Releasing hanging note {key} on channel {channel}");
+                fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel as u8, key });
+            }
+        }
+    }
+
+    // CC111 only marks the loop start, so jump back at the end of the song if nothing else did
+    if loop_start_emitted && !loop_end_emitted {
+        fdss_commands.push(FlanSeqCommand::JumpToLoopStart);
+    }
+
+    // Loop points from the MIDI file take priority over looping the whole song
+    if options.loop_song && !loop_start_emitted {
+        fdss_commands.insert(0, FlanSeqCommand::SetLoopStart);
+        fdss_commands.push(FlanSeqCommand::JumpToLoopStart);
+    }
+
+    if options.loop_snapshot {
+        snapshot_loop_state(&mut fdss_commands);
+    }
+
+    if let Some(loop_count) = options.loop_count {
+        unroll_loop(&mut fdss_commands, loop_count);
+    }
+
+    if let Some(program) = options.default_drum_kit {
+        for_each_sequence(&mut fdss_commands, sequential, |commands| init_drum_kit(commands, program, options));
+    }
+
+    if options.init_channels {
+        for_each_sequence(&mut fdss_commands, sequential, |commands| init_channels(commands, options));
+    }
+
+    if let Some(split) = &options.split_drums {
+        split_drums(&mut fdss_commands, split, options);
+    }
+
+    if !options.downmix.is_empty() {
+        downmix_channels(&mut fdss_commands, &options.downmix, options.downmix_policy);
+    }
+
+    for command in fdss_commands.iter_mut() {
+        if let Some(channel) = command.channel_mut() {
+            if let Some(&mapped_channel) = options.channel_map.get(channel) {
+                *channel = mapped_channel;
+            }
+        }
+    }
+
+    // Only done after mapping channels, several MIDI channels can end up on the same player channel
+    remove_redundant_commands(&mut fdss_commands);
+
+    if options.retrigger_notes {
+        fuse_retriggers(&mut fdss_commands);
+    }
+
+    // Channels that don't fit would corrupt the opcode, so drop those commands entirely
+    let max_channels = options.max_channels.min(MAX_CHANNELS);
+    if options.strict {
+        if let Some(channel) = fdss_commands.iter().filter_map(FlanSeqCommand::channel).find(|&channel| channel >= max_channels) {
+            return Err(ConvertError::ChannelOverflow { channel, max_channels });
+        }
+    }
+    fdss_commands.retain(|command| match command.channel() {
+        Some(channel) if channel >= max_channels => {
+            error!("Dropping {command:?}, channel {channel} is outside of the supported range 0-{}", max_channels as i32 - 1);
+            false
+        }
+        _ => true,
+    });
+
+    Ok(fdss_commands)
+}
+
+/// Reads a MIDI file and converts it to a complete .dss file
+pub fn convert_file(path: &Path, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let midi = fs::read(path).map_err(|source| ConvertError::Io { path: path.to_path_buf(), source })?;
+    convert_bytes(&midi, options)
+}
+
+/// Converts a MIDI file in memory straight to a complete .dss file
+pub fn convert_bytes(midi: &[u8], options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let smf = Smf::parse(midi)?;
+    let commands = convert_smf(&smf, options)?;
+    Ok(write_dss(&commands))
+}
+
+/// Serializes a stream of FlanSeq commands into raw sequence data
+pub fn serialize_commands(commands: &[FlanSeqCommand]) -> Vec<u8> {
+    let mut output = Vec::<u8>::new();
+    for command in commands {
+        output.extend(command.serialize());
+    }
+    output
+}
+
+/// Builds a complete .dss file, header included, from a stream of FlanSeq commands.
+/// Each `SectionStart` command begins a new section, empty sections are left out.
+pub fn write_dss(commands: &[FlanSeqCommand]) -> Vec<u8> {
+    let mut section_data: Vec<Vec<u8>> = commands
+        .split(|command| *command == FlanSeqCommand::SectionStart)
+        .map(serialize_commands)
+        .filter(|data| !data.is_empty())
+        .collect();
+    // The player still expects at least one section, even if there's nothing to play
+    if section_data.is_empty() {
+        section_data.push(Vec::new());
+    }
+
+    let sections: Vec<SectionInfo> = section_data.iter().map(|data| SectionInfo { size: data.len() }).collect();
+    let mut output = write_header(&sections);
+    output.extend(section_data.concat());
+    write_checksum(&mut output);
+    output
+}
+
+/// Name of each track that has a track name meta event, by track index.
+/// In format 0 and 1 files the name of the first track is the name of the whole song.
+pub fn track_names(smf: &Smf) -> BTreeMap<usize, String> {
+    smf.tracks.iter().enumerate().filter_map(|(track_index, track)| {
+        track.iter().find_map(|event| match event.kind {
+            TrackEventKind::Meta(midly::MetaMessage::TrackName(name)) => Some((track_index, String::from_utf8_lossy(name).trim().to_string())),
+            _ => None,
+        })
+    }).collect()
+}
+
+/// Whether a track is part of the conversion, according to the track selection options
+fn track_included(track_index: usize, options: &ConvertOptions) -> bool {
+    options.tracks.as_ref().is_none_or(|tracks| tracks.contains(&track_index)) && !options.exclude_tracks.contains(&track_index)
+}
+
+/// Finds channels where more than one track sets the same state to different values, like two tracks setting a different
+/// program on channel 0. After merging the tracks, whichever change comes last wins, which is usually not what the file meant.
+/// Returns the indices of the tracks involved, by channel
+pub fn channel_conflicts(smf: &Smf, options: &ConvertOptions) -> BTreeMap<u8, BTreeSet<usize>> {
+    // Tracks in a format 2 file are separate songs, they don't share channel state
+    if smf.header.format == midly::Format::Sequential {
+        return BTreeMap::new();
+    }
+
+    // Tracks and values for each piece of channel state
+    let mut state_tracks: HashMap<(u8, u8, u8), BTreeSet<usize>> = HashMap::new();
+    let mut state_values: HashMap<(u8, u8, u8), BTreeSet<u16>> = HashMap::new();
+    for (track_index, track) in smf.tracks.iter().enumerate().filter(|&(track_index, _)| track_included(track_index, options)) {
+        for event in track {
+            let TrackEventKind::Midi { message, .. } = event.kind else { continue };
+            let value = match message {
+                midly::MidiMessage::Controller { value, .. } => u16::from(u8::from(value)),
+                midly::MidiMessage::ProgramChange { program } => u16::from(u8::from(program)),
+                midly::MidiMessage::PitchBend { bend } => bend.0.as_int(),
+                midly::MidiMessage::ChannelAftertouch { vel } => u16::from(u8::from(vel)),
+                _ => continue,
+            };
+            let Some(key) = state_key(&event.kind) else { continue };
+            state_tracks.entry(key).or_default().insert(track_index);
+            state_values.entry(key).or_default().insert(value);
+        }
+    }
+
+    let mut conflicts: BTreeMap<u8, BTreeSet<usize>> = BTreeMap::new();
+    for (key, tracks) in state_tracks {
+        let (_, channel, _) = key;
+        if tracks.len() > 1 && state_values[&key].len() > 1 {
+            conflicts.entry(channel).or_default().extend(tracks);
+        }
+    }
+    conflicts
+}
+
+/// Logs an event that has to be dropped, as an error in strict mode so the conversion can fail after all of them are listed
+fn report_unsupported(description: &str, time: u32, options: &ConvertOptions, strict_violations: &mut usize) {
+    if options.strict {
+        error!("Unsupported {description} at tick {time}");
+        *strict_violations += 1;
+    } else {
+        debug!("Unsupported {description}");
+    }
+}
+
+/// Runs a pass that sets things up at the start of the song on every section separately if the sections are
+/// independent sequences, like in format 2, or on the whole song at once otherwise
+fn for_each_sequence(fdss_commands: &mut Vec<FlanSeqCommand>, sequential: bool, mut pass: impl FnMut(&mut Vec<FlanSeqCommand>)) {
+    if !sequential {
+        pass(fdss_commands);
+        return;
+    }
+    let mut sequences: Vec<Vec<FlanSeqCommand>> = fdss_commands.split(|command| *command == FlanSeqCommand::SectionStart).map(<[_]>::to_vec).collect();
+    sequences.iter_mut().for_each(&mut pass);
+    *fdss_commands = sequences.join(&FlanSeqCommand::SectionStart);
+}
+
+/// Inserts default channel state at the start of the song for channels that play notes
+/// before setting their volume, panning or instrument, so they don't inherit stale state from the previous song
+fn init_channels(fdss_commands: &mut Vec<FlanSeqCommand>, options: &ConvertOptions) {
+    let mut has_volume = [false; 16];
+    let mut has_panning = [false; 16];
+    let mut has_instrument = [false; 16];
+    let mut plays_notes = [false; 16];
+
+    for command in fdss_commands.iter() {
+        match *command {
+            FlanSeqCommand::SetChannelVolume { channel, .. } if !plays_notes[channel as usize] => has_volume[channel as usize] = true,
+            FlanSeqCommand::SetChannelPanning { channel, .. } if !plays_notes[channel as usize] => has_panning[channel as usize] = true,
+            FlanSeqCommand::SetChannelInstrument { channel, .. } if !plays_notes[channel as usize] => has_instrument[channel as usize] = true,
+            FlanSeqCommand::PlayNote { channel, .. } => plays_notes[channel as usize] = true,
+            _ => {}
+        }
+    }
+
+    let mut init_commands = Vec::new();
+    for channel in 0..16u8 {
+        let ch = channel as usize;
+        if !plays_notes[ch] {
+            continue;
+        }
+        if !has_volume[ch] {
+            init_commands.push(FlanSeqCommand::SetChannelVolume { channel, volume: effective_volume(channel, 100 << 7, 127 << 7, options) });
+        }
+        if !has_panning[ch] {
+            init_commands.push(FlanSeqCommand::SetChannelPanning { channel, panning: map_pan(64, options.pan_law) });
+        }
+        if !has_instrument[ch] {
+            init_commands.push(FlanSeqCommand::SetChannelInstrument { channel, index: instrument_index(channel, 0, 0, options) });
+        }
+    }
+    fdss_commands.splice(0..0, init_commands);
+}
+
+/// Selects a drum kit on channel 9 at the start of the song if it plays notes before a program change picks one.
+/// GM players start out on the standard kit, but the player would keep whatever instrument the previous song left there
+fn init_drum_kit(fdss_commands: &mut Vec<FlanSeqCommand>, program: u8, options: &ConvertOptions) {
+    let Some(first_note) = fdss_commands.iter().position(|command| matches!(command, FlanSeqCommand::PlayNote { channel: 9, .. })) else { return };
+    if fdss_commands[..first_note].iter().any(|command| matches!(command, FlanSeqCommand::SetChannelInstrument { channel: 9, .. })) {
+        return;
+    }
+    fdss_commands.insert(0, FlanSeqCommand::SetChannelInstrument { channel: 9, index: instrument_index(9, 0, program, options) });
+}
+
+/// Re-emits the state at the loop start right after `SetLoopStart`, for every piece of state that changes inside the loop,
+/// so jumping back to the loop start doesn't carry over the state from the end of the loop
+fn snapshot_loop_state(fdss_commands: &mut Vec<FlanSeqCommand>) {
+    let Some(loop_start) = fdss_commands.iter().position(|command| *command == FlanSeqCommand::SetLoopStart) else { return };
+    let loop_end = fdss_commands.iter().rposition(|command| *command == FlanSeqCommand::JumpToLoopStart).unwrap_or(fdss_commands.len());
+
+    // The last command of each kind before the loop start holds its value
+    let changed_in_loop: HashSet<_> = fdss_commands[loop_start..loop_end].iter().filter_map(command_state_key).collect();
+
+    let mut seen_state = HashSet::new();
+    let mut snapshot: Vec<FlanSeqCommand> = fdss_commands[..loop_start].iter().rev()
+        .filter(|command| command_state_key(command).is_some_and(|key| changed_in_loop.contains(&key) && seen_state.insert(key)))
+        .copied()
+        .collect();
+    snapshot.reverse();
+    debug!("Restoring {} state commands at the loop start", snapshot.len());
+    fdss_commands.splice(loop_start + 1..loop_start + 1, snapshot);
+}
+
+/// Size of an unrolled loop past which it's worth a warning, it's a good chunk of the PlayStation's 2 MiB of RAM
+const UNROLLED_LOOP_WARNING_SIZE: usize = 256 * 1024;
+
+/// Replaces the loop with `loop_count` copies of it, playback carries on after the loop end once they're done
+fn unroll_loop(fdss_commands: &mut Vec<FlanSeqCommand>, loop_count: u32) {
+    let loop_start = fdss_commands.iter().position(|command| *command == FlanSeqCommand::SetLoopStart);
+    let loop_end = loop_start.and_then(|loop_start| {
+        Some(loop_start + fdss_commands[loop_start..].iter().position(|command| *command == FlanSeqCommand::JumpToLoopStart)?)
+    });
+    let (Some(loop_start), Some(loop_end)) = (loop_start, loop_end) else {
+        debug!("Song has no loop to unroll");
+        return;
+    };
+    let loop_commands = fdss_commands[loop_start + 1..loop_end].to_vec();
+    let loop_size: usize = loop_commands.iter().map(|command| command.serialize().len()).sum();
+    let unrolled_size = loop_size * loop_count as usize;
+    if unrolled_size > UNROLLED_LOOP_WARNING_SIZE {
+        warn!("Unrolling the loop {loop_count} times takes {unrolled_size} bytes, that might not fit in the player's memory");
+    }
+    let unrolled = std::iter::repeat_n(loop_commands, loop_count as usize).flatten();
+    fdss_commands.splice(loop_start..=loop_end, unrolled);
+}
+
+/// Drops commands that set state to the value it already has, like a DAW sending the same CC7 value over and over,
+/// or dense tempo automation where many MIDI tempos round to the same raw tempo.
+/// The state is unknown again after a loop start or section start, since it can be reached from somewhere else
+fn remove_redundant_commands(fdss_commands: &mut Vec<FlanSeqCommand>) {
+    let mut known_state = HashMap::new();
+    let command_count = fdss_commands.len();
+    fdss_commands.retain(|command| {
+        if matches!(command, FlanSeqCommand::SetLoopStart | FlanSeqCommand::SectionStart) {
+            known_state.clear();
+        }
+        match command_state_key(command) {
+            Some(key) => known_state.insert(key, *command) != Some(*command),
+            None => true,
+        }
+    });
+    debug!("Removed {} redundant commands", command_count - fdss_commands.len());
+}
+
+/// Moves the notes of each drum group that's played from channel 9 to a channel nothing else uses,
+/// along with a copy of every other channel 9 command so those channels use the same drum kit, volume, etc.
+fn split_drums(fdss_commands: &mut Vec<FlanSeqCommand>, split: &DrumSplit, options: &ConvertOptions) {
+    let note_key = |command: &FlanSeqCommand| match *command {
+        FlanSeqCommand::PlayNote { key, .. } | FlanSeqCommand::ReleaseNote { key, .. }
+        | FlanSeqCommand::ReleaseNoteVelocity { key, .. } | FlanSeqCommand::RetriggerNote { key, .. }
+        | FlanSeqCommand::SetNoteAftertouch { key, .. } => Some(key),
+        _ => None,
+    };
+    let played_keys: HashSet<u8> = fdss_commands.iter().filter(|command| command.channel() == Some(9)).filter_map(note_key).collect();
+
+    // The channel map is applied afterwards, so the channels it moves commands from or to are taken as well
+    let mut used_channels: HashSet<u8> = fdss_commands.iter().filter_map(FlanSeqCommand::channel).collect();
+    used_channels.extend(options.channel_map.keys().chain(options.channel_map.values()));
+    used_channels.insert(9);
+
+    // Groups are MIDI keys, the commands have the keys after drum note mapping
+    let mut key_channels = HashMap::new();
+    let mut split_channels = Vec::new();
+    for group in &split.groups {
+        let keys: Vec<u8> = group.iter().filter_map(|&key| map_key(9, key, options)).filter(|key| played_keys.contains(key)).collect();
+        if keys.is_empty() {
+            continue;
+        }
+        let Some(channel) = (0..options.max_channels.min(MAX_CHANNELS)).rev().find(|channel| !used_channels.contains(channel)) else {
+            warn!("No free channel left for drum keys {group:?}, leaving them on channel 9");
+            continue;
+        };
+        debug!("Moving drum keys {group:?} to channel {channel}");
+        used_channels.insert(channel);
+        split_channels.push(channel);
+        key_channels.extend(keys.into_iter().map(|key| (key, channel)));
+    }
+
+    let mut split_commands = Vec::with_capacity(fdss_commands.len());
+    for &command in fdss_commands.iter() {
+        if command.channel() != Some(9) {
+            split_commands.push(command);
+            continue;
+        }
+        match note_key(&command).and_then(|key| key_channels.get(&key)) {
+            Some(&channel) => split_commands.push(with_channel(command, channel)),
+            None if note_key(&command).is_some() => split_commands.push(command),
+            None => {
+                split_commands.push(command);
+                split_commands.extend(split_channels.iter().map(|&channel| with_channel(command, channel)));
+            }
+        }
+    }
+    *fdss_commands = split_commands;
+}
+
+/// Folds the channels of each downmix group onto its target channels, which play one note at a time each.
+/// Every other command from the group's channels goes to all of its targets, so when the instruments or volumes
+/// of the folded channels conflict, the most recent one wins
+fn downmix_channels(fdss_commands: &mut Vec<FlanSeqCommand>, groups: &[DownmixGroup], policy: DownmixPolicy) {
+    // The note each target channel is playing, as its source channel, key and the position of its PlayNote
+    let mut voices: Vec<Vec<Option<(u8, u8, usize)>>> = groups.iter().map(|group| vec![None; group.targets.len()]).collect();
+    let mut next_voice = vec![0; groups.len()];
+
+    let mut mixed_commands = Vec::with_capacity(fdss_commands.len());
+    for (index, &command) in fdss_commands.iter().enumerate() {
+        let Some((source, group_index)) = command.channel()
+            .and_then(|channel| Some((channel, groups.iter().position(|group| group.sources.contains(&channel))?))) else {
+            mixed_commands.push(command);
+            continue;
+        };
+        let targets = &groups[group_index].targets;
+        let voices = &mut voices[group_index];
+        match command {
+            FlanSeqCommand::PlayNote { key, velocity, .. } => {
+                let voice = match policy {
+                    DownmixPolicy::LastNote => voices.iter().position(Option::is_none).unwrap_or_else(|| {
+                        (0..voices.len()).min_by_key(|&voice| voices[voice].map(|(_, _, start)| start)).unwrap_or(0)
+                    }),
+                    DownmixPolicy::RoundRobin => {
+                        let voice = next_voice[group_index];
+                        next_voice[group_index] = (voice + 1) % targets.len();
+                        voice
+                    }
+                };
+                if let Some((_, playing_key, _)) = voices[voice] {
+                    mixed_commands.push(FlanSeqCommand::ReleaseNote { channel: targets[voice], key: playing_key });
+                }
+                voices[voice] = Some((source, key, index));
+                mixed_commands.push(FlanSeqCommand::PlayNote { channel: targets[voice], key, velocity });
+            }
+            // Notes that were cut off by a newer one have nothing left to release
+            FlanSeqCommand::ReleaseNote { key, .. } | FlanSeqCommand::ReleaseNoteVelocity { key, .. } | FlanSeqCommand::SetNoteAftertouch { key, .. } => {
+                let Some(voice) = voices.iter().position(|&playing| playing.is_some_and(|(playing_source, playing_key, _)| (playing_source, playing_key) == (source, key))) else {
+                    continue;
+                };
+                if !matches!(command, FlanSeqCommand::SetNoteAftertouch { .. }) {
+                    voices[voice] = None;
+                }
+                mixed_commands.push(with_channel(command, targets[voice]));
+            }
+            _ => mixed_commands.extend(targets.iter().map(|&target| with_channel(command, target))),
+        }
+    }
+    *fdss_commands = mixed_commands;
+}
+
+/// Copy of a channel command, moved to another channel
+fn with_channel(mut command: FlanSeqCommand, channel: u8) -> FlanSeqCommand {
+    if let Some(command_channel) = command.channel_mut() {
+        *command_channel = channel;
+    }
+    command
+}
+
+/// Replaces every ReleaseNote that's directly followed by a PlayNote of the same key on the same channel with a RetriggerNote
+fn fuse_retriggers(fdss_commands: &mut Vec<FlanSeqCommand>) {
+    let mut fused_commands = Vec::with_capacity(fdss_commands.len());
+    let mut commands = fdss_commands.iter().copied().peekable();
+    while let Some(command) = commands.next() {
+        if let (FlanSeqCommand::ReleaseNote { channel, key }, Some(&FlanSeqCommand::PlayNote { channel: play_channel, key: play_key, velocity })) = (command, commands.peek()) {
+            if channel == play_channel && key == play_key {
+                commands.next();
+                fused_commands.push(FlanSeqCommand::RetriggerNote { channel, key, velocity });
+                continue;
+            }
+        }
+        fused_commands.push(command);
+    }
+    debug!("Fused {} note releases and plays into retriggers", fdss_commands.len() - fused_commands.len());
+    *fdss_commands = fused_commands;
+}
+
+/// Identifies the piece of state a command sets by its variant and channel, or `None` if it doesn't set any lasting state
+fn command_state_key(command: &FlanSeqCommand) -> Option<(&'static str, Option<u8>)> {
+    match command {
+        FlanSeqCommand::ReleaseNote { .. } | FlanSeqCommand::ReleaseNoteVelocity { .. } | FlanSeqCommand::PlayNote { .. } | FlanSeqCommand::RetriggerNote { .. }
+        | FlanSeqCommand::SetNoteAftertouch { .. }
+        | FlanSeqCommand::SetChannelParam { .. }
+        | FlanSeqCommand::SetChannelPortamentoSource { .. } => None,
+        _ if command.channel().is_some() => Some((command.name(), command.channel())),
+        FlanSeqCommand::SetTempo { .. } | FlanSeqCommand::SetTimeSignature { .. } | FlanSeqCommand::SetMasterVolume { .. } => Some((command.name(), None)),
+        _ => None,
+    }
+}
+
+/// Maps a MIDI program to the instrument index used by the player
+fn instrument_index(channel: u8, bank: u16, program: u8, options: &ConvertOptions) -> u8 {
+    let default_index = match channel {
+        9 => program + 128,
+        _ => program,
+    };
+    match &options.instrument_map {
+        Some(instrument_map) => match instrument_map.lookup_program(channel, bank, program) {
+            Some(index) => index,
+            None => {
+                warn!("No instrument mapping for program {program} (bank {bank}) on channel {channel}, using index {default_index}");
+                default_index
+            }
+        },
+        None => default_index,
+    }
+}
+
+/// Combines the 14-bit channel volume and expression into the volume sent to the player
+fn effective_volume(channel: u8, volume: u16, expression: u16, options: &ConvertOptions) -> u8 {
+    let volume = (volume as f32 / 128.0) * (expression as f32 / 128.0) / 127.0;
+    let volume_scale = options.channel_volume_scale.get(&channel).copied().unwrap_or(1.0);
+    (volume * volume_scale).clamp(0.0, 127.0) as u8
+}
+
+/// Converts a 14-bit RPN 1 (fine tuning) value to 10ths of cents.
+/// 8192 is centered, and the full range covers -100 to +99.99 cents, so each step is 100/8192 cents.
+fn fine_tuning_from_rpn(value: u16) -> f32 {
+    (value as f32 - 8192.0) * 100.0 / 8192.0 * 10.0
+}
+
+/// Rounds a channel pitch in 10ths of cents to the player's pitch value, clamping it to the i16 range with a warning
+fn channel_pitch(channel: u8, pitch_in_10th_of_cents: f32) -> i16 {
+    let rounded_pitch = pitch_in_10th_of_cents.round();
+    let pitch = rounded_pitch.clamp(i16::MIN as f32, i16::MAX as f32);
+    if pitch != rounded_pitch {
+        warn!("Pitch of {} cents on channel {channel} is out of range, clamping to {} cents", rounded_pitch / 10.0, pitch / 10.0);
+    }
+    pitch as i16
+}
+
+/// Looks up the marker id for a marker or cue point text in the instrument map
+fn marker_id(text: &str, options: &ConvertOptions) -> Option<u8> {
+    options.instrument_map.as_ref()?.markers.get(text.trim()).copied()
+}
+
+fn is_end_of_track(event: &TrackEventKind) -> bool {
+    matches!(event, TrackEventKind::Meta(midly::MetaMessage::EndOfTrack))
+}
+
+/// Reads the volume from a universal SysEx master volume message (F0 7F <device> 04 01 <lsb> <msb> F7),
+/// scaled from 14 bits down to 0-127. Returns `None` for any other SysEx message
+fn master_volume(data: &[u8]) -> Option<u8> {
+    // midly leaves out the leading F0, the trailing F7 is usually there
+    let (&[0x7F, _device, 0x04, 0x01, lsb, msb] | &[0x7F, _device, 0x04, 0x01, lsb, msb, 0xF7]) = data else { return None };
+    let volume = (u32::from(msb & 0x7F) << 7) | u32::from(lsb & 0x7F);
+    Some(((volume * 127 + 8191) / 16383) as u8)
+}
+
+/// Whether the event starts a note, NoteOn with velocity 0 doesn't count as it's a NoteOff
+fn is_note_on(event: &TrackEventKind) -> bool {
+    note_on_key(event).is_some()
+}
+
+/// Channel and key of a note the event starts
+fn note_on_key(event: &TrackEventKind) -> Option<(u8, u8)> {
+    match *event {
+        TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOn { key, vel } } if vel.as_int() > 0 => Some((channel.as_int(), key.as_int())),
+        _ => None,
+    }
+}
+
+/// Channel and key of a note the event releases, including NoteOns with velocity 0
+fn note_off_key(event: &TrackEventKind) -> Option<(u8, u8)> {
+    match *event {
+        TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOff { key, .. } } => Some((channel.as_int(), key.as_int())),
+        TrackEventKind::Midi { channel, message: midly::MidiMessage::NoteOn { key, vel } } if vel.as_int() == 0 => Some((channel.as_int(), key.as_int())),
+        _ => None,
+    }
+}
+
+/// Whether the event sets up state that should still apply when the events before it are cut off,
+/// that's everything except notes, loop points and markers
+fn carries_state(event: &TrackEventKind) -> bool {
+    match event {
+        TrackEventKind::Midi { message, .. } => match message {
+            midly::MidiMessage::NoteOn { .. } | midly::MidiMessage::NoteOff { .. } | midly::MidiMessage::Aftertouch { .. } => false,
+            midly::MidiMessage::Controller { controller, .. } => controller.as_int() != 111,
+            _ => true,
+        },
+        TrackEventKind::Meta(message) => matches!(message, midly::MetaMessage::Tempo(_) | midly::MetaMessage::TimeSignature(..)),
+        TrackEventKind::SysEx(data) => master_volume(data).is_some(),
+        _ => false,
+    }
+}
+
+/// Identifies which piece of state an event sets, so only the latest one needs to be kept.
+/// Returns `None` for events that only make sense together with the ones around them, like RPN data entry
+fn state_key(event: &TrackEventKind) -> Option<(u8, u8, u8)> {
+    match *event {
+        TrackEventKind::Midi { channel, message } => match message {
+            midly::MidiMessage::Controller { controller, .. } => match controller.as_int() {
+                6 | 38 | 98..=101 => None,
+                controller => Some((0, channel.as_int(), controller)),
+            },
+            midly::MidiMessage::ProgramChange { .. } => Some((1, channel.as_int(), 0)),
+            midly::MidiMessage::PitchBend { .. } => Some((2, channel.as_int(), 0)),
+            midly::MidiMessage::ChannelAftertouch { .. } => Some((3, channel.as_int(), 0)),
+            _ => None,
+        },
+        TrackEventKind::Meta(midly::MetaMessage::Tempo(_)) => Some((4, 0, 0)),
+        TrackEventKind::Meta(midly::MetaMessage::TimeSignature(..)) => Some((5, 0, 0)),
+        TrackEventKind::SysEx(data) if master_volume(data).is_some() => Some((6, 0, 0)),
+        _ => None,
+    }
+}
+
+/// Maps a note's key to the key sent to the player, returning `None` if the note should be dropped
+fn map_key(channel: u8, key: u8, options: &ConvertOptions) -> Option<u8> {
+    fit_note_range(channel, map_key_unranged(channel, key, options)?, options)
+}
+
+/// Whether a mapped key is outside the note range, drums are never affected
+fn outside_note_range(channel: u8, key: u8, options: &ConvertOptions) -> bool {
+    channel != 9 && options.note_range.is_some_and(|range| !range.contains(key))
+}
+
+/// Moves a mapped key into the note range, or returns `None` if out of range notes get dropped.
+/// NoteOffs go through this too, so they're dropped along with their NoteOn
+fn fit_note_range(channel: u8, key: u8, options: &ConvertOptions) -> Option<u8> {
+    let Some(range) = options.note_range.filter(|_| outside_note_range(channel, key, options)) else { return Some(key) };
+    if options.drop_out_of_range_notes {
+        debug!("Dropping note {key} on channel {channel}, it's outside the note range");
+        return None;
+    }
+    Some(key.clamp(range.low, range.high))
+}
+
+/// Applies the drum note mapping and transposition to a note's key, returning `None` if the note should be dropped
+fn map_key_unranged(channel: u8, key: u8, options: &ConvertOptions) -> Option<u8> {
+    let mut key = key;
+    if channel == 9 {
+        if let Some(drum_notes) = options.instrument_map.as_ref().map(|map| &map.drum_notes).filter(|notes| !notes.is_empty()) {
+            key = match drum_notes.get(&key) {
+                Some(&mapped_key) => mapped_key,
+                None if options.drop_unmapped_drums => {
+                    debug!("Dropping drum note {key}, it has no drum note mapping");
+                    return None;
+                }
+                None => {
+                    debug!("Drum note {key} has no drum note mapping, passing it through");
+                    key
+                }
+            };
+        }
+    }
+
+    if options.transpose != 0 && (channel != 9 || options.transpose_drums) {
+        let transposed_key = key as i32 + options.transpose;
+        let clamped_key = transposed_key.clamp(0, 127);
+        if transposed_key != clamped_key {
+            debug!("Note {key} on channel {channel} transposed out of range, clamping to {clamped_key}");
+        }
+        key = clamped_key as u8;
+    }
+    Some(key)
+}
+
+/// Appends the combination of WaitTicks commands needed to wait for `delta_time` ticks, none of them longer than `max_wait`
+fn push_wait_ticks(fdss_commands: &mut Vec<FlanSeqCommand>, delta_time: u32, max_wait: Option<u32>, wait_lut: &WaitLut) {
+    let wait_lut = &wait_lut.0;
+    let first_wait = fdss_commands.len();
+    let mut delta_time_left = delta_time;
+    let max_wait = max_wait.unwrap_or(u32::MAX);
+    let max_lut_wait = wait_lut[wait_lut.len() - 1] as u32;
+    while delta_time_left > 0 {
+        // Long gaps would take a lot of max length waits, so use a single long wait instead,
+        // unless the cap is short enough that the LUT can cover it
+        if delta_time_left > max_lut_wait * 4 && max_wait > max_lut_wait {
+            let ticks = delta_time_left.min(u16::MAX as u32).min(max_wait);
+            delta_time_left -= ticks;
+            fdss_commands.push(FlanSeqCommand::WaitTicksLong { ticks: ticks as u16 });
+            continue;
+        }
+
+        // Greedily take the biggest wait that still fits, the LUT is sorted so we can binary search it
+        let fitting_entries = wait_lut.partition_point(|&ticks| ticks as u32 <= delta_time_left.min(max_wait));
+        if fitting_entries == 0 {
+            error!("Could not represent a wait of {delta_time} ticks, {delta_time_left} ticks left over");
+            break;
+        }
+        let index = fitting_entries - 1;
+        delta_time_left -= wait_lut[index] as u32;
+        fdss_commands.push(FlanSeqCommand::WaitTicks { index_into_lut: index });
+    }
+
+    // Guards against LUT changes that make some gaps impossible to represent exactly, the song would drift out of time
+    let emitted_ticks: u32 = fdss_commands[first_wait..].iter().map(|command| command.wait_ticks_with(wait_lut)).sum();
+    if emitted_ticks != delta_time {
+        error!("Waits for a gap of {delta_time} ticks add up to {emitted_ticks} ticks, timing will be off from here on");
+    }
+}
+
+/// Converts a tempo in BPM to the player's 12-bit tempo value, see [`clamp_tempo`] for what happens if it's out of range
+fn tempo_from_bpm(tempo_bpm: f64, ticks_per_quarter_note: f64, options: &ConvertOptions) -> Result<u16, ConvertError> {
+    let seconds_per_quarter_note = 60.0 / tempo_bpm;
+    let seconds_per_tick = seconds_per_quarter_note / ticks_per_quarter_note;
+    clamp_tempo(tempo_from_seconds_per_tick(seconds_per_tick, options.tick_length_multiplier), options, || {
+        format!("Tempo of {tempo_bpm:.2} BPM can't be represented")
+    })
+}
+
+/// Warns and uses the clamped tempo if it was out of range, or fails in strict mode
+fn clamp_tempo(tempo: Result<u16, u16>, options: &ConvertOptions, description: impl FnOnce() -> String) -> Result<u16, ConvertError> {
+    match tempo {
+        Ok(tempo) => Ok(tempo),
+        Err(_) if options.strict => Err(ConvertError::TempoOutOfRange(description())),
+        Err(clamped_tempo) => {
+            warn!("{}, clamping to raw tempo value {clamped_tempo}", description());
+            Ok(clamped_tempo)
+        }
+    }
+}
+
+/// Converts a tick length to the player's 12-bit tempo value, or returns the clamped value as an error if it's out of range
+fn tempo_from_seconds_per_tick(seconds_per_tick: f64, tick_length_multiplier: f64) -> Result<u16, u16> {
+    let raw_value = (seconds_per_tick * tick_length_multiplier).round();
+    let clamped_value = raw_value.clamp(0.0, 4095.0);
+    if raw_value == clamped_value {
+        Ok(raw_value as u16)
+    } else {
+        Err(clamped_value as u16)
+    }
+}
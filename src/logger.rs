@@ -0,0 +1,37 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Minimal logger that prints info/debug to stdout and warnings/errors to stderr
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+/// Whether all messages should go to stderr, to keep stdout free for output data
+static STDERR_ONLY: AtomicBool = AtomicBool::new(false);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("error: {}", record.args()),
+            Level::Warn => eprintln!("warning: {}", record.args()),
+            _ if STDERR_ONLY.load(Ordering::Relaxed) => eprintln!("{}", record.args()),
+            _ => println!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(verbose: bool, stderr_only: bool) {
+    STDERR_ONLY.store(stderr_only, Ordering::Relaxed);
+    log::set_logger(&LOGGER).expect("Logger was already initialized");
+    log::set_max_level(if verbose { LevelFilter::Debug } else { LevelFilter::Info });
+}
@@ -1,201 +1,561 @@
+use log::{error, info, warn};
+use midi2psx::{commands_to_json, convert_smf, disassemble, dump_events, track_names, verify_commands, write_dss, ConversionStats, ConvertError, DrumSplit, ConvertOptions, InstrumentMap, WaitLut, MAX_CHANNELS};
 use midly::Smf;
-use midly::TrackEventKind;
 use std::env;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::{collections::BTreeMap, fs};
 
-fn main() {
-    // Get the command-line arguments
-    let args: Vec<String> = env::args().collect();
+mod logger;
 
-    if args.len() < 2 {
-        println!("Usage: midi2psx <input.mid> [output.dss]");
-        exit(1)
+const USAGE: &str = "Usage: midi2psx <input.mid|input.midi> [output.dss] [options]\n       midi2psx <input.mid|directory>... [options]\n       midi2psx --disasm <input.dss>";
+
+fn print_help() {
+    println!("midi2psx {} - converts MIDI files to FlanSeq (.dss) sequences for the PlayStation", env!("CARGO_PKG_VERSION"));
+    println!();
+    println!("{USAGE}");
+    println!();
+    println!("Use - as the input or output path to read from stdin or write to stdout.");
+    println!();
+    println!("Options:");
+    println!("  -h, --help       Print this help text and exit");
+    println!("  -V, --version    Print the version and exit");
+    println!("      --verbose    Log unsupported events and other debug information");
+    println!("      --loop       Loop the whole song, unless the MIDI file has its own loop markers");
+    println!("      --loop-snapshot");
+    println!("                   Restore the channel state and tempo from the loop start when jumping back to it");
+    println!("      --loop-count <n>");
+    println!("                   Write the loop out n times instead of jumping back, so the song ends after playing it n times");
+    println!("      --init-channels");
+    println!("                   Set a default volume, panning and instrument on channels that don't set them before playing notes");
+    println!("      --default-drum-kit <program>");
+    println!("                   Drum kit program to select when the drum channel plays notes before a program change (default 0)");
+    println!("      --no-default-drum-kit");
+    println!("                   Leave the drum kit up to the player when the drum channel doesn't select one");
+    println!("      --no-hang    Release notes that are still playing at the end of the song");
+    println!("      --trim-tail  End the song at its last event instead of at the MIDI file's end of track");
+    println!("      --trim-leading-silence");
+    println!("                   Start the song at its first note, events before it are moved to the start");
+    println!("      --timeline   Log the wait commands emitted for every MIDI event time");
+    println!("      --strict     Fail on unsupported events other than text, and on channels or tempos out of range");
+    println!("      --retrigger-notes");
+    println!("                   Combine a note release directly followed by the same note into a single retrigger command");
+    println!("      --release-velocity");
+    println!("                   Keep the velocity of note releases, for instruments that use it");
+    println!("      --stats, --dry-run");
+    println!("                   Print statistics about the conversion instead of writing the output file");
+    println!("      --output-dir <dir>");
+    println!("                   Write output files into this directory instead of next to the input files");
+    println!("      --verify     Replay the output and warn about hanging notes, missing volumes or tempo, and commands that don't read back");
+    println!("      --json       Also write the commands to a .json file next to the output file");
+    println!("      --disasm     Print the commands in a .dss file instead of converting");
+    println!("      --dump-events");
+    println!("                   Print the merged MIDI events the conversion works from instead of converting");
+    println!("      --max-channels <n>");
+    println!("                   Drop commands for channels n and up, for players with fewer channels (default 16)");
+    println!("      --velocity-curve <linear|exp|log|sqrt>");
+    println!("                   Curve applied to note velocities (default linear)");
+    println!("      --pan-law <linear|equal-power>");
+    println!("                   How MIDI pan positions are spread over the player's panning range (default linear)");
+    println!("      --instrument-map <file>");
+    println!("                   Map MIDI programs, drum kits and drum keys using [programs], [drum_kits] and [drum_notes] sections,");
+    println!("                   programs and drum kits can be limited to a bank with \"bank:program = index\"");
+    println!("                   A [markers] section maps marker and cue point texts to marker ids, e.g. \"chorus = 1\"");
+    println!("      --drop-unmapped-drums");
+    println!("                   Drop drum notes missing from [drum_notes] instead of passing them through");
+    println!("      --split-drums");
+    println!("                   Move kicks (35-36), snares (37-40), hi-hats (42,44,46), toms (41,43,45,47,48,50)");
+    println!("                   and cymbals (49,51-53,55,57,59) to unused channels each, so they don't cut each other off");
+    println!("      --drum-groups <groups>");
+    println!("                   Split drums using these groups of MIDI keys instead, e.g. 35-36/37-40/42,44,46");
+    println!("      --overlap <retrigger|merge|ignore>");
+    println!("                   What to do when a key is pressed again while it's still held, by default both notes are kept");
+    println!("      --tempo <bpm>");
+    println!("                   Play at a fixed tempo, ignoring tempo changes in the MIDI file");
+    println!("      --default-tempo <bpm>");
+    println!("                   Tempo until the first tempo change, for MIDI files that don't start with one (default 120)");
+    println!("      --quantize <division>");
+    println!("                   Snap events to a grid, e.g. 16 for sixteenth notes");
+    println!("      --ppq-normalize <ppq>");
+    println!("                   Rescale event times to this many ticks per quarter note, tick positions in --start and --end stay");
+    println!("                   in the MIDI file's resolution");
+    println!("      --start <time>");
+    println!("                   Only convert the song from this point on, in ticks or in beats with a b suffix, e.g. 960 or 4b");
+    println!("      --end <time>");
+    println!("                   Only convert the song up to this point, in ticks or in beats with a b suffix");
+    println!("      --tracks <list>");
+    println!("                   Only convert these tracks, e.g. 0,2,3");
+    println!("      --exclude-tracks <list>");
+    println!("                   Leave these tracks out of the conversion");
+    println!("      --tempo-track <n>");
+    println!("                   Only use tempo and time signature changes from this track");
+    println!("      --channel-map <map>");
+    println!("                   Move MIDI channels to other player channels, e.g. 0=3,1=4,9=15");
+    println!("      --channel-volume-scale <map>");
+    println!("                   Scale the volume of MIDI channels, e.g. 2=0.5,3=0.8");
+    println!("      --no-bend <list>");
+    println!("                   Drop pitch bends on these MIDI channels, e.g. 9,10");
+    println!("      --tick-multiplier <value>");
+    println!("                   Raw tempo value per second of tick length, for players with a modified timer (default 49152)");
+    println!("      --max-wait-coalesce <ticks>");
+    println!("                   Split up waits so no single wait command is longer than this, for players that stream the song in");
+    println!("                   and need to read a command at least every so many ticks");
+    println!("      --wait-lut <file>");
+    println!("                   Read the player's wait table from a file instead of using the built-in one, as 32 comma separated");
+    println!("                   tick counts in ascending order, starting at 1");
+    println!("      --mono <list>");
+    println!("                   Only play one note at a time on these MIDI channels, a new note releases the previous one");
+    println!("      --downmix <groups>");
+    println!("                   Fold MIDI channels onto fewer channels that play one note at a time each, e.g. \"0,1,2=0;3,4,5=1,2\".");
+    println!("                   Other commands go to all of a group's channels, so conflicting instruments or volumes take the most recent one");
+    println!("      --downmix-policy <last-note|round-robin>");
+    println!("                   Play new notes on a free channel or the one with the oldest note, or on each channel in turn (default last-note)");
+    println!("      --transpose <semitones>");
+    println!("                   Shift every note up or down, except on the drum channel");
+    println!("      --transpose-drums");
+    println!("                   Also apply --transpose to the drum channel");
+    println!("      --clamp-notes <low>-<high>");
+    println!("                   Move notes outside this key range into it, except on the drum channel, e.g. 36-96");
+    println!("      --drop-out-of-range");
+    println!("                   Drop notes outside the --clamp-notes range instead of moving them");
+    println!();
+    println!("Exit codes:");
+    println!("  1  invalid command line arguments");
+    println!("  2  failed to read an input file");
+    println!("  3  input file is not a valid MIDI file or instrument map");
+    println!("  4  failed to write an output file, or some files in a batch failed");
+    println!("  5  unsupported events in --strict mode");
+    println!("  6  time division can't be used with --start or --end");
+    println!("  7  channels out of range in --strict mode");
+    println!("  8  tempo out of range in --strict mode");
+}
+
+/// Exit code for each kind of conversion error, listed in the help text
+fn exit_code(err: &ConvertError) -> i32 {
+    match err {
+        ConvertError::Io { .. } => 2,
+        ConvertError::Parse(_) | ConvertError::InstrumentMap(_) | ConvertError::WaitLut(_) => 3,
+        ConvertError::Write { .. } => 4,
+        ConvertError::UnsupportedEvents(_) => 5,
+        ConvertError::UnsupportedTiming(_) => 6,
+        ConvertError::ChannelOverflow { .. } => 7,
+        ConvertError::TempoOutOfRange(_) => 8,
+    }
+}
+
+/// Whether the path has a MIDI file extension (.mid or .midi, in any case)
+fn is_midi_path(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".mid") || path.ends_with(".midi")
+}
+
+/// Removes every occurrence of a flag from the arguments, returning whether it was present
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let arg_count = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != arg_count
+}
+
+/// Prints the contents of a .dss file in human readable form
+fn run_disassembler(path: &str, wait_lut: &WaitLut) {
+    let bytes = match fs::read(path) {
+        Ok(x) => x,
+        Err(_) => {error!("Failed to open file {}", path); exit(2)},
+    };
+    match disassemble(&bytes, wait_lut) {
+        Ok(lines) => lines.iter().for_each(|line| println!("{line}")),
+        Err(err) => {error!("Failed to disassemble {}: {err}", path); exit(3)},
     }
+}
 
-    if args[1].ends_with(".mid") == false {
-        println!("Usage: midi2psx <input.mid> [output.dss]");
+/// Removes an option and its value from the arguments, returning the value if the option was present
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == option)?;
+    if index + 1 >= args.len() {
+        println!("Missing value for {option}");
+        println!("{USAGE}");
         exit(1)
     }
+    args.remove(index);
+    Some(args.remove(index))
+}
 
-    // Load MIDI file
-    let bytes = match fs::read(
-        &args[1],
-    ) {
+/// Parses an option value, printing the usage and exiting if it's invalid
+fn parse_or_exit<T: std::str::FromStr>(value: &str, option: &str) -> T {
+    match value.parse() {
         Ok(x) => x,
-        Err(_) => {println!("Failed to open file {}", args[1]); exit(2)},
+        Err(_) => {
+            println!("Invalid value \"{value}\" for {option}");
+            println!("{USAGE}");
+            exit(1)
+        }
+    }
+}
+
+/// Parses a comma separated option value, printing the usage and exiting if any item is invalid
+fn parse_list_or_exit<T: std::str::FromStr>(value: &str, option: &str) -> Vec<T> {
+    value.split(',').map(|item| parse_or_exit(item.trim(), option)).collect()
+}
+
+/// Parses a comma separated list of key=value pairs, printing the usage and exiting if any of them are invalid
+fn parse_map_or_exit<K: std::str::FromStr + std::hash::Hash + Eq, V: std::str::FromStr>(value: &str, option: &str) -> HashMap<K, V> {
+    value.split(',').map(|item| match item.split_once('=') {
+        Some((key, value)) => (parse_or_exit(key.trim(), option), parse_or_exit(value.trim(), option)),
+        None => {
+            println!("Invalid value \"{item}\" for {option}, expected key=value");
+            println!("{USAGE}");
+            exit(1)
+        }
+    }).collect()
+}
+
+fn main() {
+    // Get the command-line arguments, without the executable name
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_help();
+        exit(0)
+    }
+
+    if args.iter().any(|arg| arg == "-V" || arg == "--version") {
+        println!("midi2psx {}", env!("CARGO_PKG_VERSION"));
+        exit(0)
+    }
+
+    // Flags can go anywhere, so strip them out before looking at the positional arguments
+    let verbose = take_flag(&mut args, "--verbose");
+    let disasm = take_flag(&mut args, "--disasm");
+    let cli_options = CliOptions {
+        stats: take_flag(&mut args, "--stats") | take_flag(&mut args, "--dry-run"),
+        json: take_flag(&mut args, "--json"),
+        verify: take_flag(&mut args, "--verify"),
+        dump_events: take_flag(&mut args, "--dump-events"),
+        output_dir: take_option(&mut args, "--output-dir").map(PathBuf::from),
+    };
+    let mut options = ConvertOptions {
+        loop_song: take_flag(&mut args, "--loop"),
+        drop_unmapped_drums: take_flag(&mut args, "--drop-unmapped-drums"),
+        drop_out_of_range_notes: take_flag(&mut args, "--drop-out-of-range"),
+        transpose_drums: take_flag(&mut args, "--transpose-drums"),
+        release_hanging_notes: take_flag(&mut args, "--no-hang"),
+        trim_tail: take_flag(&mut args, "--trim-tail"),
+        trim_leading_silence: take_flag(&mut args, "--trim-leading-silence"),
+        init_channels: take_flag(&mut args, "--init-channels"),
+        loop_snapshot: take_flag(&mut args, "--loop-snapshot"),
+        strict: take_flag(&mut args, "--strict"),
+        timeline: take_flag(&mut args, "--timeline"),
+        release_velocity: take_flag(&mut args, "--release-velocity"),
+        retrigger_notes: take_flag(&mut args, "--retrigger-notes"),
+        ..Default::default()
     };
-    let smf = Smf::parse(&bytes).unwrap();
+    if let Some(max_channels) = take_option(&mut args, "--max-channels") {
+        options.max_channels = parse_or_exit(&max_channels, "--max-channels");
+    }
+    if let Some(velocity_curve) = take_option(&mut args, "--velocity-curve") {
+        options.velocity_curve = parse_or_exit(&velocity_curve, "--velocity-curve");
+    }
+    if let Some(pan_law) = take_option(&mut args, "--pan-law") {
+        options.pan_law = parse_or_exit(&pan_law, "--pan-law");
+    }
+    if let Some(overlap) = take_option(&mut args, "--overlap") {
+        options.overlap = Some(parse_or_exit(&overlap, "--overlap"));
+    }
+    let instrument_map_path = take_option(&mut args, "--instrument-map");
+    let wait_lut_path = take_option(&mut args, "--wait-lut");
+    if take_flag(&mut args, "--split-drums") {
+        options.split_drums = Some(DrumSplit::default());
+    }
+    if let Some(drum_groups) = take_option(&mut args, "--drum-groups") {
+        options.split_drums = Some(parse_or_exit(&drum_groups, "--drum-groups"));
+    }
+    if let Some(tempo_bpm) = take_option(&mut args, "--tempo") {
+        options.tempo_bpm = Some(parse_or_exit(&tempo_bpm, "--tempo"));
+    }
+    if let Some(default_tempo_bpm) = take_option(&mut args, "--default-tempo") {
+        options.default_tempo_bpm = parse_or_exit(&default_tempo_bpm, "--default-tempo");
+    }
+    if let Some(quantize) = take_option(&mut args, "--quantize") {
+        options.quantize = Some(parse_or_exit(&quantize, "--quantize"));
+    }
+    if let Some(ppq) = take_option(&mut args, "--ppq-normalize") {
+        let ppq: u16 = parse_or_exit(&ppq, "--ppq-normalize");
+        if !(1..=0x7FFF).contains(&ppq) {
+            println!("Value for --ppq-normalize must be between 1 and 32767");
+            exit(1)
+        }
+        options.ppq_normalize = Some(ppq);
+    }
+    if let Some(start) = take_option(&mut args, "--start") {
+        options.start = Some(parse_or_exit(&start, "--start"));
+    }
+    if let Some(end) = take_option(&mut args, "--end") {
+        options.end = Some(parse_or_exit(&end, "--end"));
+    }
+    if let Some(tracks) = take_option(&mut args, "--tracks") {
+        options.tracks = Some(parse_list_or_exit(&tracks, "--tracks"));
+    }
+    if let Some(exclude_tracks) = take_option(&mut args, "--exclude-tracks") {
+        options.exclude_tracks = parse_list_or_exit(&exclude_tracks, "--exclude-tracks");
+    }
+    if let Some(tempo_track) = take_option(&mut args, "--tempo-track") {
+        options.tempo_track = Some(parse_or_exit(&tempo_track, "--tempo-track"));
+    }
+    if let Some(channel_map) = take_option(&mut args, "--channel-map") {
+        options.channel_map = parse_map_or_exit(&channel_map, "--channel-map");
+        if options.channel_map.values().chain(options.channel_map.keys()).any(|&channel| channel >= MAX_CHANNELS) {
+            println!("Channels for --channel-map must be between 0 and {}", MAX_CHANNELS - 1);
+            exit(1)
+        }
+    }
+    if let Some(channel_volume_scale) = take_option(&mut args, "--channel-volume-scale") {
+        options.channel_volume_scale = parse_map_or_exit(&channel_volume_scale, "--channel-volume-scale");
+    }
+    if let Some(no_bend_channels) = take_option(&mut args, "--no-bend") {
+        options.no_bend_channels = parse_list_or_exit(&no_bend_channels, "--no-bend");
+    }
+    if let Some(downmix) = take_option(&mut args, "--downmix") {
+        options.downmix = downmix.split(';').map(|group| parse_or_exit(group.trim(), "--downmix")).collect();
+    }
+    if let Some(downmix_policy) = take_option(&mut args, "--downmix-policy") {
+        options.downmix_policy = parse_or_exit(&downmix_policy, "--downmix-policy");
+    }
+    if let Some(default_drum_kit) = take_option(&mut args, "--default-drum-kit") {
+        options.default_drum_kit = Some(parse_or_exit(&default_drum_kit, "--default-drum-kit"));
+    }
+    if take_flag(&mut args, "--no-default-drum-kit") {
+        options.default_drum_kit = None;
+    }
+    if let Some(loop_count) = take_option(&mut args, "--loop-count") {
+        let loop_count: u32 = parse_or_exit(&loop_count, "--loop-count");
+        if loop_count == 0 {
+            println!("Value for --loop-count must be greater than 0");
+            exit(1)
+        }
+        options.loop_count = Some(loop_count);
+    }
+    if let Some(mono_channels) = take_option(&mut args, "--mono") {
+        options.mono_channels = parse_list_or_exit(&mono_channels, "--mono");
+    }
+    if let Some(max_wait_ticks) = take_option(&mut args, "--max-wait-coalesce") {
+        let max_wait_ticks: u32 = parse_or_exit(&max_wait_ticks, "--max-wait-coalesce");
+        if max_wait_ticks == 0 {
+            println!("Value for --max-wait-coalesce must be greater than 0");
+            exit(1)
+        }
+        options.max_wait_ticks = Some(max_wait_ticks);
+    }
+    if let Some(tick_length_multiplier) = take_option(&mut args, "--tick-multiplier") {
+        options.tick_length_multiplier = parse_or_exit(&tick_length_multiplier, "--tick-multiplier");
+        if !options.tick_length_multiplier.is_finite() || options.tick_length_multiplier <= 0.0 {
+            println!("Value for --tick-multiplier must be greater than 0");
+            exit(1)
+        }
+    }
+    if let Some(note_range) = take_option(&mut args, "--clamp-notes") {
+        options.note_range = Some(parse_or_exit(&note_range, "--clamp-notes"));
+    }
+    if let Some(transpose) = take_option(&mut args, "--transpose") {
+        options.transpose = parse_or_exit(&transpose, "--transpose");
+    }
 
-    // Find output path
-    let out_path;
-    if args.len() < 3 {
-        out_path = args[1].replace(".mid", ".dss");
-    } else {
-        out_path = args[2].clone();
+    if args.is_empty() {
+        println!("{USAGE}");
+        exit(1)
+    }
+
+    // Output data on stdout must not get mixed up with log messages
+    let writes_to_stdout = args.get(1).map_or(args[0] == "-", |out_path| out_path == "-");
+    logger::init(verbose, writes_to_stdout);
+
+    if let Some(path) = instrument_map_path {
+        match load_instrument_map(Path::new(&path)) {
+            Ok(instrument_map) => options.instrument_map = Some(instrument_map),
+            Err(err) => {error!("Failed to load instrument map {}: {err}", path); exit(exit_code(&err))},
+        }
+    }
+    if let Some(path) = wait_lut_path {
+        match load_wait_lut(Path::new(&path)) {
+            Ok(wait_lut) => options.wait_lut = wait_lut,
+            Err(err) => {error!("Failed to load wait table {}: {err}", path); exit(exit_code(&err))},
+        }
     }
 
-    // Read all the tracks and events, and squash them together into one track
-    let mut event_map = BTreeMap::new();
+    if disasm {
+        run_disassembler(&args[0], &options.wait_lut);
+        exit(0)
+    }
 
-    for (_, track) in smf.tracks.iter().enumerate() {
-        let mut time = 0;
-        for event in track {
-            time += event.delta.as_int();
-            event_map.entry(time).or_insert(Vec::new()).push(event.kind);
+    if let Some(output_dir) = &cli_options.output_dir {
+        if let Err(err) = fs::create_dir_all(output_dir) {
+            error!("Failed to create output directory {}: {err}", output_dir.display());
+            exit(2)
         }
     }
 
-    // Now let's convert it into FlanSeqCommands
-    let mut fdss_commands: Vec<FlanSeqCommand> = Vec::new();
-    let mut prev_time = 0;
-    let mut pitch_bend_range_coarse = 2;
-    let mut pitch_bend_range_fine = 0;
-    for (time, events) in event_map {
-        if prev_time != time {
-            let delta_time = time - prev_time;
+    // A directory or a list of MIDI files means batch conversion, with the output paths derived from the inputs
+    let batch = args.iter().any(|arg| Path::new(arg).is_dir()) || (args.len() > 1 && args.iter().all(|arg| is_midi_path(arg)));
+    if batch {
+        let mut in_paths = Vec::new();
+        for arg in &args {
+            let path = Path::new(arg);
+            if path.is_dir() {
+                in_paths.extend(find_midi_files(path));
+            } else {
+                in_paths.push(path.to_path_buf());
+            }
+        }
 
-            // Figure out what combination of ticks is necessary
-            let mut delta_time_left = delta_time as u16;
-            while delta_time_left > 0 {
-            for index in (0..WAIT_TICK_LUT.len()).rev() {
-                if WAIT_TICK_LUT[index] <= delta_time_left {
-                    delta_time_left -= WAIT_TICK_LUT[index];
-                    fdss_commands.push(FlanSeqCommand::WaitTicks { index_into_lut: index });
-                    break;
+        let mut failed_count = 0;
+        let mut out_paths = HashSet::new();
+        for in_path in &in_paths {
+            let out_path = output_path(in_path, &cli_options);
+            // Inputs with the same name from different directories end up at the same path in the output directory
+            if !out_paths.insert(out_path.clone()) {
+                error!("Not converting {}, {} was already written by another input file", in_path.display(), out_path.display());
+                failed_count += 1;
+                continue;
+            }
+            match convert_file(in_path, &out_path, &options, &cli_options) {
+                Ok(()) => info!("Converted {}", in_path.display()),
+                Err(err) => {
+                    error!("Failed to convert {}: {err}", in_path.display());
+                    failed_count += 1;
                 }
-            }}
-        }
-        prev_time = time;
-        let mut cc100 = -1;
-        let mut cc101 = -1;
-        for event in events {
-            match event {
-                TrackEventKind::Midi {channel, message} => {
-                    match message {
-                        midly::MidiMessage::NoteOn{key, vel} => fdss_commands.push(FlanSeqCommand::PlayNote { channel: channel.into(), key: key.into(), velocity: vel.into() }),
-                        midly::MidiMessage::NoteOff{key, vel: _} => fdss_commands.push(FlanSeqCommand::ReleaseNote { channel: channel.into(), key: key.into() }),
-                        midly::MidiMessage::ProgramChange{program} => {
-                            let channel = u8::from(channel);
-                            let index = match channel {
-                                9 => u8::from(program) + 128,
-                                _ => u8::from(program),
-                            };
-                            fdss_commands.push(FlanSeqCommand::SetChannelInstrument { channel: channel, index: index })
-                        },
-                        midly::MidiMessage::PitchBend {bend} => {
-                            let pitch_bend_range_cents = (pitch_bend_range_coarse as f32 * 100.0) + (pitch_bend_range_fine as f32 * 1.0);
-                            let bend_value_normalized = bend.as_f32();
-                            let actual_bend_in_10th_of_cents = (pitch_bend_range_cents * 10.0) * bend_value_normalized;
-                            fdss_commands.push(FlanSeqCommand::SetChannelPitch { channel: channel.into(), pitch: actual_bend_in_10th_of_cents as i16 })
-                        },
-                        midly::MidiMessage::Controller{controller, value} => match u8::from(controller) {
-                            7 => fdss_commands.push(FlanSeqCommand::SetChannelVolume { channel: channel.into(), volume: value.into() }),
-                            10 => fdss_commands.push(FlanSeqCommand::SetChannelPanning { channel: channel.into(), panning: u8::from(value) * 2 }),
-                            100 => cc100 = u8::from(value) as i32,
-                            101 => cc101 = u8::from(value) as i32,
-                            6 => {
-                                if cc100 == 0 && cc101 == 0 {
-                                    pitch_bend_range_coarse = value.into()
-                                }
-                            }
-                            38 => {
-                                if cc100 == 0 && cc101 == 0 {
-                                    pitch_bend_range_fine = value.into()
-                                }
-                            }
-                            _ => println!("Unsupported controller {controller}, value {value}"),
-                        }
-                        _ => println!("Unsupported event {message:?}"),
-                    }
-                },
-                TrackEventKind::Meta(message) => {
-                    match message {
-                        midly::MetaMessage::Tempo(tempo) => {
-                            let ticks_per_quarter_note = match smf.header.timing {
-                                midly::Timing::Metrical(ticks_per_quarter_note) => ticks_per_quarter_note.as_int() as f64,
-                                midly::Timing::Timecode(..) => panic!("Attempted tempo change with fixed timecode for time division!")
-                            };
-                            let microseconds_per_quarter_note = tempo.as_int() as f64;
-                            let microseconds_per_tick = microseconds_per_quarter_note / ticks_per_quarter_note;
-                            let seconds_per_tick = microseconds_per_tick /  1_000_000.0;
-                            let tick_length_multiplier = 49152.0;
-                            let raw_value = (seconds_per_tick * tick_length_multiplier).round().clamp(0.0, 4095.0);
-                            fdss_commands.push(FlanSeqCommand::SetTempo { tempo: raw_value as u16 })
-                        },
-                        midly::MetaMessage::TimeSignature(num, denom, _ticks_per_click, _note32_per_midi_quarter) => {
-                            fdss_commands.push(FlanSeqCommand::SetTimeSignature { numerator: num, denominator: 1 << denom })
-                        },
-                        _ => println!("Unsupported meta event {message:?}"),
-                    }
-                },
-                _ => println!("Unsupported event: {event:?}"),
             }
         }
+        info!("Converted {} of {} files, {failed_count} failed", in_paths.len() - failed_count, in_paths.len());
+        exit(if failed_count > 0 { 4 } else { 0 })
+    }
+
+    if !is_midi_path(&args[0]) && args[0] != "-" {
+        println!("{USAGE}");
+        exit(1)
+    }
+
+    // Find output path, "-" means stdout
+    let in_path = Path::new(&args[0]);
+    let out_path = if args.len() < 2 {
+        // Only swap out the final extension, ".mid" may also show up in directory or file names
+        match args[0].as_str() {
+            "-" => PathBuf::from("-"),
+            _ => output_path(in_path, &cli_options),
+        }
+    } else {
+        PathBuf::from(&args[1])
+    };
+
+    if let Err(err) = convert_file(in_path, &out_path, &options, &cli_options) {
+        error!("Failed to convert {}: {err}", in_path.display());
+        exit(exit_code(&err))
+    }
+}
+
+/// Options that affect what the command line tool does with the converted commands
+struct CliOptions {
+    /// Print statistics instead of writing the output file
+    stats: bool,
+    /// Also write the commands to a .json file
+    json: bool,
+    /// Check the converted commands for signs of conversion bugs
+    verify: bool,
+    /// Print the merged MIDI events instead of converting them
+    dump_events: bool,
+    /// Directory to write output files to when they're derived from the input path, instead of next to the input
+    output_dir: Option<PathBuf>,
+}
+
+/// Derives the output path from the input path, swapping out the final extension for .dss
+fn output_path(in_path: &Path, cli_options: &CliOptions) -> PathBuf {
+    let out_path = in_path.with_extension("dss");
+    match (&cli_options.output_dir, out_path.file_name()) {
+        (Some(output_dir), Some(file_name)) => output_dir.join(file_name),
+        _ => out_path,
+    }
+}
+
+/// Lists the MIDI files directly inside a directory, sorted by name
+fn find_midi_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(err) => {error!("Failed to read directory {}: {err}", dir.display()); return Vec::new()},
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_midi_path(&path.to_string_lossy()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Reads and parses an instrument map file
+fn load_instrument_map(path: &Path) -> Result<InstrumentMap, ConvertError> {
+    let text = fs::read_to_string(path).map_err(|source| ConvertError::Io { path: path.to_path_buf(), source })?;
+    InstrumentMap::parse(&text).map_err(ConvertError::InstrumentMap)
+}
+
+/// Reads and parses a wait table file
+fn load_wait_lut(path: &Path) -> Result<WaitLut, ConvertError> {
+    let text = fs::read_to_string(path).map_err(|source| ConvertError::Io { path: path.to_path_buf(), source })?;
+    text.parse().map_err(ConvertError::WaitLut)
+}
+
+/// Converts a single MIDI file
+fn convert_file(in_path: &Path, out_path: &Path, options: &ConvertOptions, cli_options: &CliOptions) -> Result<(), ConvertError> {
+    // Load MIDI file, "-" means stdin
+    let bytes = if in_path == Path::new("-") {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map_err(|source| ConvertError::Io { path: in_path.to_path_buf(), source })?;
+        bytes
+    } else {
+        fs::read(in_path).map_err(|source| ConvertError::Io { path: in_path.to_path_buf(), source })?
+    };
+    let smf = Smf::parse(&bytes)?;
+    if cli_options.dump_events {
+        for line in dump_events(&smf, options)? {
+            println!("{line}");
+        }
+        return Ok(())
     }
 
-    // TODO: write header
-    let mut output = Vec::<u8>::new();
-    output.extend("FDDS".as_bytes());  // file magic
-    output.extend(1u32.to_le_bytes()); // number of sections, currently forced to 1
-    output.extend(0u32.to_le_bytes()); // section table offset, let's just define this to be the first thing after the header
-    output.extend(4u32.to_le_bytes()); // section data offset, always 4 because number of sections is forced to 1
-    output.extend(0u32.to_le_bytes()); // section table entry 1: starts at the start of the section data
+    // Convert to FlanSeqCommands and write them to a file
+    let fdss_commands = convert_smf(&smf, options)?;
+    if cli_options.verify {
+        let problems = verify_commands(&fdss_commands, &options.wait_lut);
+        for problem in &problems {
+            warn!("Verify: {problem}");
+        }
+        if problems.is_empty() {
+            info!("Verify: no problems found");
+        }
+    }
+    if cli_options.stats {
+        let stats = ConversionStats { track_names: track_names(&smf), ..ConversionStats::from_commands_with(&fdss_commands, options) };
+        print!("{stats}");
+        return Ok(())
+    }
+    let output = write_dss(&fdss_commands);
 
-    // Write sequence data to file
-    for command in fdss_commands {
-        output.extend(command.serialize());
+    let to_stdout = out_path == Path::new("-");
+    if cli_options.json && to_stdout {
+        warn!("Not writing JSON file, output is going to stdout");
+    } else if cli_options.json {
+        let json_path = out_path.with_extension("json");
+        if let Err(err) = fs::write(&json_path, commands_to_json(&fdss_commands, &options.wait_lut)) {
+            error!("Error writing to file {}: {}", json_path.display(), err);
+        }
     }
 
-    if let Err(err) = fs::write(out_path, &output) {
-        eprintln!("Error writing to file: {}", err);
+    let result = if to_stdout {
+        io::stdout().write_all(&output).and_then(|_| io::stdout().flush())
     } else {
-        println!("Data successfully written to file.");
-    }
-}
-
-#[derive(Debug)]
-pub enum FlanSeqCommand {
-    // Channel commands
-    ReleaseNote{channel: u8, key: u8},
-    PlayNote{channel: u8, key: u8, velocity: u8},
-    SetChannelVolume{channel: u8, volume: u8},
-    SetChannelPanning{channel: u8, panning:u8},
-    SetChannelPitch{channel: u8, pitch: i16},
-    SetChannelInstrument{channel: u8, index: u8},
-
-    // General commands
-    SetTempo{tempo: u16},
-    WaitTicks{index_into_lut: usize},
-    SetTimeSignature{numerator: u8, denominator: u8},
-    SetLoopStart,
-    JumpToLoopStart,
-}
-
-impl FlanSeqCommand {
-    fn serialize(self) -> Vec<u8> {
-        match self {
-            FlanSeqCommand::ReleaseNote         { channel, key } =>               return vec![0x00 | channel, key],
-            FlanSeqCommand::PlayNote            { channel, key, velocity } => return vec![0x10 | channel, key, velocity],
-            FlanSeqCommand::SetChannelVolume    { channel, volume } =>            return vec![0x20 | channel, volume],
-            FlanSeqCommand::SetChannelPanning   { channel, panning } =>           return vec![0x30 | channel, panning],
-            FlanSeqCommand::SetChannelPitch     { channel, pitch } => {
-                let pitch_bytes = pitch.to_le_bytes();
-                return vec![0x40 | channel, pitch_bytes[0], pitch_bytes[1]]
-            },
-            FlanSeqCommand::SetChannelInstrument { channel, index } =>            return vec![0x50 | channel, index],
-            FlanSeqCommand::SetTempo            { tempo } =>                         return vec![0x80 | (tempo >> 8) as u8, (tempo & 0xFF) as u8],
-            FlanSeqCommand::WaitTicks { index_into_lut } =>                        return vec![0xA0 + index_into_lut as u8],
-            FlanSeqCommand::SetTimeSignature { numerator, denominator } =>        return vec![0xFD, numerator, denominator],
-            FlanSeqCommand::SetLoopStart =>                                               return vec![0xFE],
-            FlanSeqCommand::JumpToLoopStart =>                                            return vec![0xFF],
-        }
-    }
-}
-
-const WAIT_TICK_LUT: [u16; 32] = [
-    1,      2,      3,      4,      6,      8,      12,     16,
-    20,     24,     28,     32,     40,     48,     56,     64,
-    80,     96,     112,    128,    160,    192,    224,    256,
-    320,    384,    448,    512,    640,    768,    896,    1024,
-];
\ No newline at end of file
+        fs::write(out_path, &output)
+    };
+    result.map_err(|source| ConvertError::Write { path: out_path.to_path_buf(), source })?;
+    info!("Data successfully written to file.");
+    let stats = ConversionStats::from_commands_with(&fdss_commands, options);
+    info!("Wrote {} bytes, {:.2} seconds long, {} notes on {} channels", stats.total_bytes, stats.total_seconds, stats.note_count, stats.channels_used.len());
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::str::FromStr;
+
+/// Range of keys the player's instruments can play, inclusive on both ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteRange {
+    pub low: u8,
+    pub high: u8,
+}
+
+impl NoteRange {
+    pub fn contains(&self, key: u8) -> bool {
+        (self.low..=self.high).contains(&key)
+    }
+}
+
+/// Parses a range like "36-96"
+impl FromStr for NoteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_key = |key: &str| key.trim().parse::<u8>().ok().filter(|&key| key < 128);
+        let range = s.split_once('-').and_then(|(low, high)| Some(NoteRange { low: parse_key(low)?, high: parse_key(high)? }));
+        match range {
+            Some(range) if range.low <= range.high => Ok(range),
+            _ => Err(format!("Invalid note range \"{s}\", expected low-high with keys from 0 to 127")),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::str::FromStr;
+
+/// What to do when a key is pressed again on a channel while it's still held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Release the held note and play the new one, the note ends at the last release
+    Retrigger,
+    /// Keep the held note playing and drop the new one, the note ends at the last release
+    Merge,
+    /// Drop the new note, the note ends at the first release
+    Ignore,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "retrigger" => Ok(OverlapPolicy::Retrigger),
+            "merge" => Ok(OverlapPolicy::Merge),
+            "ignore" => Ok(OverlapPolicy::Ignore),
+            _ => Err(format!("Unknown overlap policy \"{s}\"")),
+        }
+    }
+}
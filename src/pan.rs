@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+/// How MIDI pan positions are spread over the player's panning range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanLaw {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl FromStr for PanLaw {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(PanLaw::Linear),
+            "equal-power" => Ok(PanLaw::EqualPower),
+            _ => Err(format!("Unknown pan law \"{s}\"")),
+        }
+    }
+}
+
+/// Maps a MIDI pan value (0-127, center 64) to the player's panning (0 = hard left, 128 = center, 255 = hard right).
+///
+/// With the linear law each half is scaled separately, so that both the center and the hard right extreme line up.
+/// The player splits the panning linearly over both sides, so for the equal-power law the angle θ = pan * π/2
+/// is turned into the panning that gives the same left/right balance as cos θ and sin θ: sin θ / (sin θ + cos θ).
+/// The center and both extremes stay where they are, positions in between end up closer to the center than with the linear law.
+pub fn map_pan(value: u8, law: PanLaw) -> u8 {
+    let value = value.min(127) as u32;
+    match law {
+        PanLaw::Linear if value <= 64 => (value * 2) as u8,
+        PanLaw::Linear => (128 + ((value - 64) * 127 + 31) / 63) as u8,
+        PanLaw::EqualPower => {
+            // Same split around the center as the linear law, so 64 is exactly the center
+            let position = if value <= 64 { value as f64 / 128.0 } else { 0.5 + (value - 64) as f64 / 126.0 };
+            let angle = position * std::f64::consts::FRAC_PI_2;
+            let balance = angle.sin() / (angle.sin() + angle.cos());
+            if balance <= 0.5 {
+                (balance * 256.0).round() as u8
+            } else {
+                (128.0 + (balance - 0.5) * 254.0).round() as u8
+            }
+        }
+    }
+}
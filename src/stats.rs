@@ -0,0 +1,87 @@
+use crate::{write_dss, ConvertOptions, FlanSeqCommand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Summary of a converted command stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConversionStats {
+    /// Number of commands of each variant, by name
+    pub command_counts: BTreeMap<&'static str, usize>,
+    /// Size of the .dss file, header included
+    pub total_bytes: usize,
+    /// Channels that have at least one command
+    pub channels_used: BTreeSet<u8>,
+    /// Lowest and highest raw tempo value set, if any
+    pub tempo_range: Option<(u16, u16)>,
+    /// Length of the song in ticks
+    pub total_ticks: u64,
+    /// Length of the song in seconds, ticks before the first tempo change aren't counted since their length is up to the player
+    pub total_seconds: f64,
+    /// Number of notes played, retriggered notes included
+    pub note_count: usize,
+    /// Names of the MIDI tracks, by track index, see [`crate::track_names`]. Not filled in by `from_commands`
+    pub track_names: BTreeMap<usize, String>,
+}
+
+impl ConversionStats {
+    /// Summarizes commands converted with the default options, so with the stock player's wait table and timer
+    pub fn from_commands(commands: &[FlanSeqCommand]) -> Self {
+        Self::from_commands_with(commands, &ConvertOptions::default())
+    }
+
+    /// Summarizes commands converted with these options, the wait table and tick length multiplier decide how long the song is
+    pub fn from_commands_with(commands: &[FlanSeqCommand], options: &ConvertOptions) -> Self {
+        // Until the first tempo change the player keeps whatever tempo it had, so there's no telling how long that part is
+        let mut seconds_per_tick = 0.0;
+        let mut stats = ConversionStats {
+            total_bytes: write_dss(commands).len(),
+            ..Default::default()
+        };
+
+        for command in commands {
+            *stats.command_counts.entry(command.name()).or_default() += 1;
+            if let Some(channel) = command.channel() {
+                stats.channels_used.insert(channel);
+            }
+            if let FlanSeqCommand::SetTempo { tempo } = *command {
+                seconds_per_tick = tempo as f64 / options.tick_length_multiplier;
+                stats.tempo_range = Some(match stats.tempo_range {
+                    Some((min, max)) => (min.min(tempo), max.max(tempo)),
+                    None => (tempo, tempo),
+                });
+            }
+            if matches!(command, FlanSeqCommand::PlayNote { .. } | FlanSeqCommand::RetriggerNote { .. }) {
+                stats.note_count += 1;
+            }
+            let ticks = command.wait_ticks_with(&options.wait_lut.0);
+            stats.total_ticks += ticks as u64;
+            stats.total_seconds += ticks as f64 * seconds_per_tick;
+        }
+
+        stats
+    }
+}
+
+impl fmt::Display for ConversionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Total size: {} bytes", self.total_bytes)?;
+        writeln!(f, "Total length: {} ticks ({:.2} seconds)", self.total_ticks, self.total_seconds)?;
+        writeln!(f, "Notes: {}", self.note_count)?;
+        writeln!(f, "Channels used: {:?}", self.channels_used)?;
+        match self.tempo_range {
+            Some((min, max)) => writeln!(f, "Tempo range: {min}-{max} (raw)")?,
+            None => writeln!(f, "Tempo range: none set")?,
+        }
+        if !self.track_names.is_empty() {
+            writeln!(f, "Tracks:")?;
+            for (track_index, name) in &self.track_names {
+                writeln!(f, "  {track_index:<4}{name}")?;
+            }
+        }
+        writeln!(f, "Commands:")?;
+        for (name, count) in &self.command_counts {
+            writeln!(f, "  {name:<22}{count:>8}")?;
+        }
+        Ok(())
+    }
+}
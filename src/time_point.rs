@@ -0,0 +1,34 @@
+use std::str::FromStr;
+
+/// A position in a song, either as a raw tick or in beats (quarter notes)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimePoint {
+    Ticks(u32),
+    Beats(f64),
+}
+
+impl TimePoint {
+    /// Converts the position to ticks, beats can only be converted with a metrical time division
+    pub fn to_ticks(self, timing: midly::Timing) -> Result<u32, String> {
+        match (self, timing) {
+            (TimePoint::Ticks(ticks), _) => Ok(ticks),
+            (TimePoint::Beats(beats), midly::Timing::Metrical(ticks_per_quarter_note)) => Ok((beats * ticks_per_quarter_note.as_int() as f64).round() as u32),
+            (TimePoint::Beats(_), midly::Timing::Timecode(..)) => Err("time division is SMPTE timecode, so there are no beats".to_string()),
+        }
+    }
+}
+
+/// Parses a plain number as ticks, and a number followed by "b" as beats, e.g. "960" or "4b"
+impl FromStr for TimePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('b') {
+            Some(beats) => match beats.parse::<f64>() {
+                Ok(beats) if beats.is_finite() && beats >= 0.0 => Ok(TimePoint::Beats(beats)),
+                _ => Err(format!("Invalid beat count \"{beats}\"")),
+            },
+            None => s.parse().map(TimePoint::Ticks).map_err(|_| format!("Invalid tick count \"{s}\"")),
+        }
+    }
+}
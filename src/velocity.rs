@@ -0,0 +1,42 @@
+use std::str::FromStr;
+
+/// Curve applied to note velocities before they're sent to the player
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VelocityCurve {
+    #[default]
+    Linear,
+    Exp,
+    Log,
+    Sqrt,
+}
+
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(VelocityCurve::Linear),
+            "exp" => Ok(VelocityCurve::Exp),
+            "log" => Ok(VelocityCurve::Log),
+            "sqrt" => Ok(VelocityCurve::Sqrt),
+            _ => Err(format!("Unknown velocity curve \"{s}\"")),
+        }
+    }
+}
+
+/// Maps a MIDI velocity through a curve. 0 and 127 always map to themselves,
+/// and non-zero velocities never map to 0 so notes don't turn into releases.
+pub fn map_velocity(v: u8, curve: VelocityCurve) -> u8 {
+    // Steepness of the exp and log curves
+    const K: f64 = 2.0;
+
+    let x = v.min(127) as f64 / 127.0;
+    let y = match curve {
+        VelocityCurve::Linear => return v,
+        VelocityCurve::Exp => (f64::exp(K * x) - 1.0) / (f64::exp(K) - 1.0),
+        VelocityCurve::Log => f64::ln(1.0 + (f64::exp(K) - 1.0) * x) / K,
+        VelocityCurve::Sqrt => x.sqrt(),
+    };
+    let mapped = (y * 127.0).round() as u8;
+    if v > 0 { mapped.max(1) } else { 0 }
+}
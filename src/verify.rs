@@ -0,0 +1,75 @@
+use crate::{read_section_offsets, write_dss, FlanSeqCommand, WaitLut};
+use std::collections::{BTreeSet, HashSet};
+
+/// Writes the commands to a .dss file, reads them back and replays them like the player would,
+/// returning a description of everything that looks like a conversion bug. An empty list means the song checks out.
+/// Waits are as long as they are in the given wait table
+pub fn verify_commands(commands: &[FlanSeqCommand], wait_lut: &WaitLut) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // Section starts don't end up in the file, everything else should come back exactly as it went in
+    let expected: Vec<FlanSeqCommand> = commands.iter().copied().filter(|command| *command != FlanSeqCommand::SectionStart).collect();
+    let decoded = match decode_dss(&write_dss(commands)) {
+        Ok(decoded) => decoded,
+        Err(err) => return vec![err],
+    };
+    if let Some(index) = expected.iter().zip(&decoded).position(|(expected, decoded)| expected != decoded) {
+        problems.push(format!("Command {index} reads back as {:?} instead of {:?}", decoded[index], expected[index]));
+    } else if expected.len() != decoded.len() {
+        problems.push(format!("File contains {} commands instead of {}", decoded.len(), expected.len()));
+    }
+    let expected_ticks: u64 = expected.iter().map(|command| command.wait_ticks_with(&wait_lut.0) as u64).sum();
+    let decoded_ticks: u64 = decoded.iter().map(|command| command.wait_ticks_with(&wait_lut.0) as u64).sum();
+    if expected_ticks != decoded_ticks {
+        problems.push(format!("File is {decoded_ticks} ticks long instead of {expected_ticks}"));
+    }
+
+    let mut tick = 0u64;
+    let mut tempo_set = false;
+    let mut playing: BTreeSet<(u8, u8)> = BTreeSet::new();
+    let mut volume_set = [false; 16];
+    let mut reported_volume: HashSet<u8> = HashSet::new();
+    for command in &decoded {
+        match *command {
+            FlanSeqCommand::SetTempo { .. } => tempo_set = true,
+            FlanSeqCommand::SetChannelVolume { channel, .. } => volume_set[channel as usize & 0x0F] = true,
+            FlanSeqCommand::PlayNote { channel, key, .. } | FlanSeqCommand::RetriggerNote { channel, key, .. } => {
+                playing.insert((channel, key));
+                if !volume_set[channel as usize & 0x0F] && reported_volume.insert(channel) {
+                    problems.push(format!("Channel {channel} plays a note at tick {tick} before its volume is set"));
+                }
+            }
+            FlanSeqCommand::ReleaseNote { channel, key } | FlanSeqCommand::ReleaseNoteVelocity { channel, key, .. } => {
+                playing.remove(&(channel, key));
+            }
+            _ => {}
+        }
+        tick += command.wait_ticks_with(&wait_lut.0) as u64;
+    }
+
+    for (channel, key) in playing {
+        problems.push(format!("Note {key} on channel {channel} is still playing at the end of the song"));
+    }
+    if !tempo_set && tick > 0 {
+        problems.push("Tempo is never set, the song plays at the player's default tempo".to_string());
+    }
+
+    problems
+}
+
+/// Reads every command from a .dss file, one section after the other
+fn decode_dss(bytes: &[u8]) -> Result<Vec<FlanSeqCommand>, String> {
+    let section_offsets = read_section_offsets(bytes).ok_or("Written file is not a valid .dss file")?;
+    let mut commands = Vec::new();
+    for (section_index, &section_start) in section_offsets.iter().enumerate() {
+        let section_end = section_offsets.get(section_index + 1).copied().unwrap_or(bytes.len());
+        let mut offset = section_start;
+        while offset < section_end {
+            let (command, size) = FlanSeqCommand::deserialize(&bytes[offset..section_end])
+                .ok_or_else(|| format!("Unknown or truncated command at offset {offset:#X}, opcode {:#04X}", bytes[offset]))?;
+            commands.push(command);
+            offset += size;
+        }
+    }
+    Ok(commands)
+}
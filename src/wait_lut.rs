@@ -0,0 +1,32 @@
+use crate::WAIT_TICK_LUT;
+use std::str::FromStr;
+
+/// Wait lengths in ticks that the player's WaitTicks opcodes index into, this has to match the player build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitLut(pub [u16; 32]);
+
+impl Default for WaitLut {
+    /// The table of the stock sequence player
+    fn default() -> Self {
+        Self(WAIT_TICK_LUT)
+    }
+}
+
+/// Parses 32 comma separated tick counts in ascending order, starting at 1 so every gap can be represented exactly
+impl FromStr for WaitLut {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries = s.split(',')
+            .map(|entry| entry.trim().parse::<u16>().map_err(|_| format!("Invalid wait length \"{}\"", entry.trim())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let entries: [u16; 32] = entries.try_into().map_err(|entries: Vec<u16>| format!("Expected 32 wait lengths, found {}", entries.len()))?;
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0] >= pair[1]) {
+            return Err(format!("Wait lengths must be in ascending order, {} is followed by {}", pair[0], pair[1]));
+        }
+        if entries[0] != 1 {
+            return Err(format!("Shortest wait length must be 1, not {}", entries[0]));
+        }
+        Ok(Self(entries))
+    }
+}
@@ -0,0 +1,247 @@
+//! Runs the midi2psx executable on files in a temporary directory, for behaviour that lives in main.rs
+
+mod common;
+
+use common::SongBuilder;
+use midly::num::u7;
+use midi2psx::{convert_bytes, convert_file, ConvertError, ConvertOptions, TimePoint};
+use midly::{Fps, MidiMessage, Timing};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// An empty directory for a single test, left behind afterwards so failures can be looked into
+fn temp_dir(test_name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("midi2psx-cli-{}-{test_name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a MIDI file with a single note, so every file converts to the same .dss
+fn write_song(path: &Path) {
+    SongBuilder::new().note(0, 60, 100, 0, 96).build().save(path).unwrap();
+}
+
+/// Writes a MIDI file with a note and two CC74 (brightness) events, which the player has no command for
+fn write_song_with_unsupported_controller(path: &Path) {
+    let brightness = |value| MidiMessage::Controller { controller: u7::new(74), value: u7::new(value) };
+    SongBuilder::new().midi(0, 0, brightness(64)).note(0, 60, 100, 0, 96).midi(48, 0, brightness(80)).build().save(path).unwrap();
+}
+
+fn midi2psx(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_midi2psx")).args(args).output().unwrap()
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn midi_extensions_are_accepted() {
+    let dir = temp_dir("extensions");
+    for name in ["a.mid", "b.midi", "c.MID"] {
+        write_song(&dir.join(name));
+        let output = midi2psx(&[path_str(&dir.join(name))]);
+        assert!(output.status.success(), "{name}: {output:?}");
+        assert!(dir.join(name).with_extension("dss").is_file(), "{name}");
+    }
+
+    write_song(&dir.join("d.txt"));
+    assert_eq!(midi2psx(&[path_str(&dir.join("d.txt"))]).status.code(), Some(1));
+}
+
+#[test]
+fn only_final_extension_is_replaced() {
+    let dir = temp_dir("final-extension").join("midi.mid");
+    fs::create_dir_all(&dir).unwrap();
+    write_song(&dir.join("song.mid.backup.mid"));
+    let output = midi2psx(&[path_str(&dir.join("song.mid.backup.mid"))]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(dir.join("song.mid.backup.dss").is_file());
+}
+
+#[test]
+fn batch_converts_directory() {
+    let dir = temp_dir("batch");
+    write_song(&dir.join("one.mid"));
+    write_song(&dir.join("two.midi"));
+    fs::write(dir.join("notes.txt"), "not a MIDI file").unwrap();
+
+    let output = midi2psx(&[path_str(&dir)]);
+    assert!(output.status.success(), "{output:?}");
+    let mut dss_files: Vec<String> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".dss"))
+        .collect();
+    dss_files.sort();
+    assert_eq!(dss_files, ["one.dss", "two.dss"]);
+}
+
+#[test]
+fn stdin_to_stdout_matches_file_output() {
+    let dir = temp_dir("stdio");
+    write_song(&dir.join("song.mid"));
+    assert!(midi2psx(&[path_str(&dir.join("song.mid"))]).status.success());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_midi2psx"))
+        .args(["-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&fs::read(dir.join("song.mid")).unwrap()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, fs::read(dir.join("song.dss")).unwrap());
+}
+
+#[test]
+fn dropped_events_are_summarized() {
+    let dir = temp_dir("dropped");
+    write_song_with_unsupported_controller(&dir.join("song.mid"));
+    let output = midi2psx(&[path_str(&dir.join("song.mid"))]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Dropped 2 unsupported controller events (CC74 x2)"), "{stdout}");
+}
+
+#[test]
+fn strict_fails_on_unsupported_controller() {
+    let dir = temp_dir("strict");
+    write_song_with_unsupported_controller(&dir.join("song.mid"));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid"))]).status.code(), Some(0));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--strict"]).status.code(), Some(5));
+}
+
+#[test]
+fn output_dir_receives_output_files() {
+    let dir = temp_dir("output-dir");
+    write_song(&dir.join("one.mid"));
+    write_song(&dir.join("two.mid"));
+    let out_dir = dir.join("out");
+
+    let output = midi2psx(&[path_str(&dir.join("one.mid")), "--output-dir", path_str(&out_dir)]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(out_dir.join("one.dss").is_file());
+    assert!(!dir.join("one.dss").exists());
+
+    let output = midi2psx(&[path_str(&dir.join("two.mid")), path_str(&dir.join("one.mid")), "--output-dir", path_str(&out_dir)]);
+    assert!(output.status.success(), "{output:?}");
+    assert!(out_dir.join("two.dss").is_file());
+    assert!(!dir.join("two.dss").exists());
+}
+
+#[test]
+fn convert_bytes_matches_cli_output() {
+    let dir = temp_dir("convert-bytes");
+    write_song_with_unsupported_controller(&dir.join("song.mid"));
+    assert!(midi2psx(&[path_str(&dir.join("song.mid")), "--loop"]).status.success());
+
+    let options = ConvertOptions { loop_song: true, ..ConvertOptions::default() };
+    let converted = convert_bytes(&fs::read(dir.join("song.mid")).unwrap(), &options).unwrap();
+    assert_eq!(converted, fs::read(dir.join("song.dss")).unwrap());
+}
+
+#[test]
+fn io_error_exits_with_2() {
+    let dir = temp_dir("exit-io");
+    assert!(matches!(convert_file(&dir.join("missing.mid"), &ConvertOptions::default()), Err(ConvertError::Io { .. })));
+    assert_eq!(midi2psx(&[path_str(&dir.join("missing.mid"))]).status.code(), Some(2));
+}
+
+#[test]
+fn parse_error_exits_with_3() {
+    let dir = temp_dir("exit-parse");
+    fs::write(dir.join("song.mid"), "not a MIDI file").unwrap();
+    assert!(matches!(convert_file(&dir.join("song.mid"), &ConvertOptions::default()), Err(ConvertError::Parse(_))));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid"))]).status.code(), Some(3));
+}
+
+#[test]
+fn instrument_map_error_exits_with_3() {
+    let dir = temp_dir("exit-instrument-map");
+    write_song(&dir.join("song.mid"));
+    fs::write(dir.join("map.txt"), "[nonsense]\n").unwrap();
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--instrument-map", path_str(&dir.join("map.txt"))]).status.code(), Some(3));
+}
+
+#[test]
+fn wait_lut_error_exits_with_3() {
+    let dir = temp_dir("exit-wait-lut");
+    write_song(&dir.join("song.mid"));
+    fs::write(dir.join("lut.txt"), "1,2,3").unwrap();
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--wait-lut", path_str(&dir.join("lut.txt"))]).status.code(), Some(3));
+}
+
+#[test]
+fn write_error_exits_with_4() {
+    let dir = temp_dir("exit-write");
+    write_song(&dir.join("song.mid"));
+    let out_path = dir.join("missing").join("song.dss");
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), path_str(&out_path)]).status.code(), Some(4));
+}
+
+#[test]
+fn unsupported_events_exit_with_5() {
+    let dir = temp_dir("exit-unsupported-events");
+    write_song_with_unsupported_controller(&dir.join("song.mid"));
+    let options = ConvertOptions { strict: true, ..ConvertOptions::default() };
+    assert!(matches!(convert_file(&dir.join("song.mid"), &options), Err(ConvertError::UnsupportedEvents(2))));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--strict"]).status.code(), Some(5));
+}
+
+#[test]
+fn unsupported_timing_exits_with_6() {
+    let dir = temp_dir("exit-unsupported-timing");
+    let mut smf = SongBuilder::new().note(0, 60, 100, 0, 96).build();
+    smf.header.timing = Timing::Timecode(Fps::Fps25, 40);
+    smf.save(dir.join("song.mid")).unwrap();
+    let options = ConvertOptions { start: Some("4b".parse::<TimePoint>().unwrap()), ..ConvertOptions::default() };
+    assert!(matches!(convert_file(&dir.join("song.mid"), &options), Err(ConvertError::UnsupportedTiming(_))));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--start", "4b"]).status.code(), Some(6));
+}
+
+#[test]
+fn channel_overflow_exits_with_7() {
+    let dir = temp_dir("exit-channel-overflow");
+    SongBuilder::new().note(5, 60, 100, 0, 96).build().save(dir.join("song.mid")).unwrap();
+    let options = ConvertOptions { strict: true, max_channels: 4, ..ConvertOptions::default() };
+    assert!(matches!(convert_file(&dir.join("song.mid"), &options), Err(ConvertError::ChannelOverflow { channel: 5, max_channels: 4 })));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--strict", "--max-channels", "4"]).status.code(), Some(7));
+}
+
+#[test]
+fn tempo_out_of_range_exits_with_8() {
+    let dir = temp_dir("exit-tempo-out-of-range");
+    write_song(&dir.join("song.mid"));
+    let options = ConvertOptions { strict: true, tempo_bpm: Some(1.0), ..ConvertOptions::default() };
+    assert!(matches!(convert_file(&dir.join("song.mid"), &options), Err(ConvertError::TempoOutOfRange(_))));
+    assert_eq!(midi2psx(&[path_str(&dir.join("song.mid")), "--strict", "--tempo", "1"]).status.code(), Some(8));
+}
+
+#[test]
+fn timeline_waits_add_up_to_deltas() {
+    let dir = temp_dir("timeline");
+    SongBuilder::new().note(0, 60, 100, 0, 100).note(0, 62, 100, 137, 5000).build().save(dir.join("song.mid")).unwrap();
+    let output = midi2psx(&[path_str(&dir.join("song.mid")), "--timeline"]);
+    assert!(output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let timeline: Vec<&str> = stdout.lines().filter(|line| line.starts_with("MIDI tick")).collect();
+    assert_eq!(timeline.len(), 3, "{stdout}");
+    for line in timeline {
+        // e.g. "MIDI tick      100: delta    100, emitted tick      100, waits [LUT[17]=96, LUT[3]=4]"
+        let number_after = |label: &str| -> u32 {
+            line.split(label).nth(1).unwrap().trim_start().split(|c: char| !c.is_ascii_digit()).next().unwrap().parse().unwrap()
+        };
+        let waits = line.split_once("waits [").unwrap().1.strip_suffix(']').unwrap();
+        let wait_sum: u32 = waits.split(", ")
+            .map(|wait| wait.strip_prefix("long ").unwrap_or_else(|| wait.split('=').nth(1).unwrap()).parse::<u32>().unwrap())
+            .sum();
+        assert_eq!(wait_sum, number_after("delta"), "{line}");
+        assert_eq!(number_after("emitted tick"), number_after("MIDI tick"), "{line}");
+    }
+}
@@ -0,0 +1,66 @@
+//! Builds MIDI files for tests out of notes and tempo changes at absolute tick positions, so tests don't need binary fixtures.
+//! Each test file only uses part of it
+#![allow(dead_code)]
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+pub const TICKS_PER_QUARTER_NOTE: u16 = 96;
+
+/// Collects events per track, events at the same tick stay in the order they were added
+pub struct SongBuilder {
+    tracks: Vec<Vec<(u32, TrackEventKind<'static>)>>,
+}
+
+impl SongBuilder {
+    /// Starts a song with a single empty track
+    pub fn new() -> Self {
+        Self { tracks: vec![Vec::new()] }
+    }
+
+    /// Adds events to a new track from here on
+    pub fn track(mut self) -> Self {
+        self.tracks.push(Vec::new());
+        self
+    }
+
+    pub fn event(mut self, at: u32, kind: TrackEventKind<'static>) -> Self {
+        self.tracks.last_mut().unwrap().push((at, kind));
+        self
+    }
+
+    pub fn midi(self, at: u32, channel: u8, message: MidiMessage) -> Self {
+        self.event(at, TrackEventKind::Midi { channel: u4::new(channel), message })
+    }
+
+    pub fn meta(self, at: u32, message: MetaMessage<'static>) -> Self {
+        self.event(at, TrackEventKind::Meta(message))
+    }
+
+    /// A NoteOn at `start` and its NoteOff `duration` ticks later
+    pub fn note(self, channel: u8, key: u8, velocity: u8, start: u32, duration: u32) -> Self {
+        self.midi(start, channel, MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(velocity) })
+            .midi(start + duration, channel, MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(0) })
+    }
+
+    pub fn tempo(self, bpm: f64, at: u32) -> Self {
+        self.meta(at, MetaMessage::Tempo(u24::new((60_000_000.0 / bpm).round() as u32)))
+    }
+
+    /// Turns the absolute times into deltas and ends every track at its last event, unless the track already has an end of track
+    pub fn build(self) -> Smf<'static> {
+        let tracks = self.tracks.into_iter().map(|mut events| {
+            events.sort_by_key(|&(time, _)| time);
+            if !matches!(events.last(), Some((_, TrackEventKind::Meta(MetaMessage::EndOfTrack)))) {
+                events.push((events.last().map_or(0, |&(time, _)| time), TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+            }
+            let mut prev_time = 0;
+            events.into_iter().map(|(time, kind)| {
+                let delta = time - prev_time;
+                prev_time = time;
+                TrackEvent { delta: u28::new(delta), kind }
+            }).collect()
+        }).collect();
+        Smf { header: Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_QUARTER_NOTE))), tracks }
+    }
+}
@@ -0,0 +1,835 @@
+//! Converts small songs built with `SongBuilder` and checks the commands that come out, for behaviour that
+//! golden files would only show as a changed byte somewhere
+
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{convert_smf, map_pan, map_velocity, read_section_offsets, serialize_commands, verify_commands, write_dss, ConvertError, ConvertOptions, FlanSeqCommand, InstrumentMap, OverlapPolicy, PanLaw, TimePoint, VelocityCurve, WaitLut, HEADER_SIZE};
+use midly::num::u7;
+use midly::{MetaMessage, MidiMessage, PitchBend, TrackEventKind};
+
+/// Converts with the given options, leaving out the waits and tempo so tests can compare just the events
+fn events(smf: &midly::Smf, options: &ConvertOptions) -> Vec<FlanSeqCommand> {
+    convert_smf(smf, options).unwrap().into_iter()
+        .filter(|command| !matches!(command, FlanSeqCommand::WaitTicks { .. } | FlanSeqCommand::WaitTicksLong { .. } | FlanSeqCommand::SetTempo { .. }))
+        .collect()
+}
+
+/// Converts with the given options, pairing every command other than waits and tempo with the tick it happens on
+fn timed_events(smf: &midly::Smf, options: &ConvertOptions) -> Vec<(u32, FlanSeqCommand)> {
+    let mut tick = 0;
+    let mut timed_events = Vec::new();
+    for command in convert_smf(smf, options).unwrap() {
+        match command {
+            FlanSeqCommand::WaitTicks { .. } | FlanSeqCommand::WaitTicksLong { .. } => tick += command.wait_ticks(),
+            FlanSeqCommand::SetTempo { .. } => {}
+            _ => timed_events.push((tick, command)),
+        }
+    }
+    timed_events
+}
+
+fn program(program: u8) -> MidiMessage {
+    MidiMessage::ProgramChange { program: u7::new(program) }
+}
+
+fn controller(controller: u8, value: u8) -> MidiMessage {
+    MidiMessage::Controller { controller: u7::new(controller), value: u7::new(value) }
+}
+
+fn bend(bend: i16) -> MidiMessage {
+    MidiMessage::PitchBend { bend: PitchBend::from_int(bend) }
+}
+
+#[test]
+fn zero_length_note_is_released() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 0).build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+    assert!(verify_commands(&commands, &WaitLut::default()).iter().all(|problem| !problem.contains("still playing")), "{commands:?}");
+}
+
+#[test]
+fn held_note_is_released_before_same_key_starts_again() {
+    // The first note ends on the tick the second one starts, and the second one starts and ends on that tick as well
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).note(0, 60, 90, 96, 0).build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 90 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+}
+
+#[test]
+fn program_change_on_another_track_comes_before_note() {
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .track()
+        .midi(0, 0, program(5))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelInstrument { channel: 0, index: 5 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+}
+
+#[test]
+fn note_on_with_velocity_0_releases_note() {
+    let note_on = |vel| MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(vel) };
+    let smf = SongBuilder::new().midi(0, 0, note_on(100)).midi(96, 0, note_on(0)).build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+}
+
+#[test]
+fn bend_range_is_per_channel() {
+    // RPN 0 sets channel 0 to 12 semitones, channel 1 keeps the default of 2
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(101, 0)).midi(0, 0, controller(100, 0)).midi(0, 0, controller(6, 12))
+        .midi(0, 0, bend(0x1000))
+        .midi(0, 1, bend(0x1000))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 6000 },
+        FlanSeqCommand::SetChannelPitch { channel: 1, pitch: 1000 },
+    ]);
+}
+
+#[test]
+fn rpn_selection_carries_over_to_later_ticks() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(101, 0)).midi(0, 0, controller(100, 0))
+        .midi(10, 0, controller(6, 12))
+        .midi(20, 0, bend(0x1000))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 6000 }]);
+}
+
+#[test]
+fn loop_markers_become_loop_commands() {
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .meta(96, MetaMessage::Marker(b" LoopStart "))
+        .note(0, 62, 100, 96, 96)
+        .meta(192, MetaMessage::Marker(b"loopend"))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+        FlanSeqCommand::SetLoopStart,
+        FlanSeqCommand::PlayNote { channel: 0, key: 62, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 62 },
+        FlanSeqCommand::JumpToLoopStart,
+    ]);
+}
+
+#[test]
+fn cc111_gives_a_single_loop_start() {
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .midi(96, 0, controller(111, 0))
+        .meta(96, MetaMessage::Marker(b"loopStart"))
+        .note(0, 62, 100, 96, 96)
+        .build();
+    let commands = events(&smf, &ConvertOptions::default());
+    assert_eq!(commands.iter().filter(|&&command| command == FlanSeqCommand::SetLoopStart).count(), 1, "{commands:?}");
+    assert_eq!(commands.last(), Some(&FlanSeqCommand::JumpToLoopStart));
+}
+
+#[test]
+fn loop_option_wraps_song() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).build();
+    let options = ConvertOptions { loop_song: true, ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    assert_eq!(commands.first(), Some(&FlanSeqCommand::SetLoopStart));
+    assert_eq!(commands.last(), Some(&FlanSeqCommand::JumpToLoopStart));
+
+    // Loop markers in the file win over the option
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .meta(96, MetaMessage::Marker(b"loopStart"))
+        .note(0, 62, 100, 96, 96)
+        .build();
+    let commands = events(&smf, &options);
+    assert_eq!(commands.iter().position(|&command| command == FlanSeqCommand::SetLoopStart), Some(2), "{commands:?}");
+    assert_eq!(commands.iter().filter(|&&command| command == FlanSeqCommand::JumpToLoopStart).count(), 1, "{commands:?}");
+}
+
+#[test]
+fn channels_past_max_channels_are_dropped() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).note(5, 64, 100, 0, 96).build();
+    let options = ConvertOptions { max_channels: 4, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+
+    let options = ConvertOptions { strict: true, ..options };
+    assert!(matches!(convert_smf(&smf, &options), Err(ConvertError::ChannelOverflow { channel: 5, max_channels: 4 })));
+
+    // Channel 16 doesn't fit in the opcode's low nibble, even with the default number of channels
+    let options = ConvertOptions { channel_map: [(0, 16)].into(), strict: true, ..ConvertOptions::default() };
+    assert!(matches!(convert_smf(&smf, &options), Err(ConvertError::ChannelOverflow { channel: 16, max_channels: 16 })));
+}
+
+#[test]
+fn exp_velocity_curve_lowers_mid_values() {
+    assert_eq!(map_velocity(0, VelocityCurve::Exp), 0);
+    assert_eq!(map_velocity(127, VelocityCurve::Exp), 127);
+    for velocity in [32, 64, 96] {
+        assert!(map_velocity(velocity, VelocityCurve::Exp) < velocity, "velocity {velocity}");
+    }
+
+    let smf = SongBuilder::new().note(0, 60, 64, 0, 96).build();
+    let options = ConvertOptions { velocity_curve: VelocityCurve::Exp, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options)[0], FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: map_velocity(64, VelocityCurve::Exp) });
+}
+
+#[test]
+fn instrument_map_sets_instrument_index() {
+    let instrument_map = InstrumentMap::parse("[programs]\n5 = 20\n[drum_kits]\n0 = 3\n").unwrap();
+    let smf = SongBuilder::new()
+        .midi(0, 0, program(5))
+        .midi(0, 1, program(7))
+        .midi(0, 9, program(0))
+        .build();
+    let options = ConvertOptions { instrument_map: Some(instrument_map), ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelInstrument { channel: 0, index: 20 },
+        FlanSeqCommand::SetChannelInstrument { channel: 1, index: 7 },
+        FlanSeqCommand::SetChannelInstrument { channel: 9, index: 3 },
+    ]);
+}
+
+#[test]
+fn drum_note_map_rewrites_drum_keys() {
+    let instrument_map = InstrumentMap::parse("[drum_notes]\n36 = 10\n").unwrap();
+    let smf = SongBuilder::new().note(9, 36, 100, 0, 48).note(9, 38, 100, 48, 48).build();
+    let options = ConvertOptions { instrument_map: Some(instrument_map), ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelInstrument { channel: 9, index: 128 },
+        FlanSeqCommand::PlayNote { channel: 9, key: 10, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 10 },
+        FlanSeqCommand::PlayNote { channel: 9, key: 38, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 38 },
+    ]);
+
+    let options = ConvertOptions { drop_unmapped_drums: true, ..options };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelInstrument { channel: 9, index: 128 },
+        FlanSeqCommand::PlayNote { channel: 9, key: 10, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 10 },
+    ]);
+}
+
+#[test]
+fn transpose_clamps_to_key_range() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 48).note(0, 120, 100, 48, 48).note(9, 36, 100, 96, 48).build();
+    let options = ConvertOptions { transpose: 12, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelInstrument { channel: 9, index: 128 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 72, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 72 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 127, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 127 },
+        FlanSeqCommand::PlayNote { channel: 9, key: 36, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 36 },
+    ]);
+
+    let options = ConvertOptions { transpose_drums: true, ..options };
+    assert!(events(&smf, &options).contains(&FlanSeqCommand::PlayNote { channel: 9, key: 48, velocity: 100 }));
+}
+
+#[test]
+fn tempo_override_gives_a_single_tempo() {
+    let smf = SongBuilder::new().tempo(140.0, 0).note(0, 60, 100, 0, 96).tempo(90.0, 48).build();
+    let options = ConvertOptions { tempo_bpm: Some(100.0), ..ConvertOptions::default() };
+    let tempos: Vec<FlanSeqCommand> = convert_smf(&smf, &options).unwrap().into_iter()
+        .filter(|command| matches!(command, FlanSeqCommand::SetTempo { .. }))
+        .collect();
+    // 100 BPM at 96 ticks per quarter note is 0.00625 seconds per tick, times the default multiplier of 49152
+    assert_eq!(tempos, [FlanSeqCommand::SetTempo { tempo: 307 }]);
+}
+
+#[test]
+fn out_of_range_tempo_is_clamped() {
+    let smf = SongBuilder::new().tempo(5.0, 0).note(0, 60, 100, 0, 96).build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    assert!(commands.contains(&FlanSeqCommand::SetTempo { tempo: 4095 }), "{commands:?}");
+
+    let options = ConvertOptions { strict: true, ..ConvertOptions::default() };
+    assert!(matches!(convert_smf(&smf, &options), Err(ConvertError::TempoOutOfRange(_))));
+}
+
+#[test]
+fn time_signature_denominator_is_capped() {
+    let smf = SongBuilder::new()
+        .meta(0, MetaMessage::TimeSignature(3, 8, 24, 8))
+        .meta(96, MetaMessage::TimeSignature(6, 3, 24, 8))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetTimeSignature { numerator: 3, denominator: 128 },
+        FlanSeqCommand::SetTimeSignature { numerator: 6, denominator: 8 },
+    ]);
+}
+
+#[test]
+fn expression_scales_channel_volume() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(7, 100))
+        .midi(0, 0, controller(11, 0))
+        .midi(48, 0, controller(11, 64))
+        .midi(96, 0, controller(11, 127))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 100 },
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 0 },
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 50 },
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 100 },
+    ]);
+}
+
+#[test]
+fn bank_select_picks_banked_program() {
+    // Banks are CC0 * 128 + CC32
+    let instrument_map = InstrumentMap::parse("[programs]\n5 = 20\n128:5 = 30\n130:5 = 40\n").unwrap();
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(0, 1)).midi(0, 0, program(5))
+        .midi(0, 1, program(5))
+        .midi(0, 2, controller(0, 1)).midi(0, 2, controller(32, 2)).midi(0, 2, program(5))
+        .build();
+    let options = ConvertOptions { instrument_map: Some(instrument_map), ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelInstrument { channel: 0, index: 30 },
+        FlanSeqCommand::SetChannelInstrument { channel: 1, index: 20 },
+        FlanSeqCommand::SetChannelInstrument { channel: 2, index: 40 },
+    ]);
+}
+
+#[test]
+fn all_notes_off_releases_playing_notes() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(100) })
+        .midi(0, 0, MidiMessage::NoteOn { key: u7::new(64), vel: u7::new(100) })
+        .midi(0, 0, MidiMessage::NoteOn { key: u7::new(67), vel: u7::new(100) })
+        .midi(0, 1, MidiMessage::NoteOn { key: u7::new(72), vel: u7::new(100) })
+        .midi(96, 0, controller(123, 0))
+        .build();
+    let commands = events(&smf, &ConvertOptions::default());
+    assert_eq!(commands[4..], [
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 64 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 67 },
+    ], "{commands:?}");
+}
+
+#[test]
+fn hanging_note_is_released_at_end() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(100) })
+        .note(1, 64, 100, 0, 96)
+        .build();
+    let hanging_release = FlanSeqCommand::ReleaseNote { channel: 0, key: 60 };
+    assert!(!convert_smf(&smf, &ConvertOptions::default()).unwrap().contains(&hanging_release));
+
+    let options = ConvertOptions { release_hanging_notes: true, ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    let release_position = commands.iter().position(|&command| command == hanging_release).unwrap();
+    let waits_before: u32 = commands[..release_position].iter().map(FlanSeqCommand::wait_ticks).sum();
+    assert_eq!(waits_before, 96, "{commands:?}");
+}
+
+#[test]
+fn quantize_snaps_events_to_grid() {
+    // Sixteenth notes are 24 ticks at 96 ticks per quarter note
+    let smf = SongBuilder::new().note(0, 60, 100, 23, 26).note(0, 62, 100, 60, 5).build();
+    let options = ConvertOptions { quantize: Some(16), ..ConvertOptions::default() };
+    assert_eq!(timed_events(&smf, &options), [
+        (24, FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 }),
+        (48, FlanSeqCommand::ReleaseNote { channel: 0, key: 60 }),
+        // Both ends of the short note snap to the same grid point, it still gets released
+        (72, FlanSeqCommand::PlayNote { channel: 0, key: 62, velocity: 100 }),
+        (72, FlanSeqCommand::ReleaseNote { channel: 0, key: 62 }),
+    ]);
+}
+
+#[test]
+fn track_selection_leaves_out_other_tracks() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).track().note(1, 64, 100, 0, 96).build();
+    let only_second_track = [
+        FlanSeqCommand::PlayNote { channel: 1, key: 64, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 1, key: 64 },
+    ];
+    let options = ConvertOptions { tracks: Some(vec![1]), ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), only_second_track);
+    let options = ConvertOptions { exclude_tracks: vec![0], ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), only_second_track);
+}
+
+#[test]
+fn channel_map_moves_every_command() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, program(3))
+        .midi(0, 0, controller(7, 100))
+        .midi(0, 0, bend(0))
+        .note(0, 60, 100, 0, 96)
+        .build();
+    let options = ConvertOptions { channel_map: [(0, 5)].into(), ..ConvertOptions::default() };
+    let commands = events(&smf, &options);
+    assert_eq!(commands.len(), 5);
+    assert!(commands.iter().all(|command| command.channel() == Some(5)), "{commands:?}");
+    assert!(commands.iter().all(|command| command.serialize()[0] & 0x0F == 5), "{commands:?}");
+}
+
+#[test]
+fn channel_volume_scale_halves_volume() {
+    let smf = SongBuilder::new().midi(0, 2, controller(7, 100)).midi(0, 3, controller(7, 100)).build();
+    let options = ConvertOptions { channel_volume_scale: [(2, 0.5), (3, 2.0)].into(), ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::SetChannelVolume { channel: 2, volume: 50 },
+        FlanSeqCommand::SetChannelVolume { channel: 3, volume: 127 },
+    ]);
+}
+
+#[test]
+fn init_channels_sets_missing_channel_state() {
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .midi(0, 1, controller(7, 80))
+        .note(1, 64, 100, 0, 96)
+        .build();
+    let options = ConvertOptions { init_channels: true, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options)[..5], [
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 100 },
+        FlanSeqCommand::SetChannelPanning { channel: 0, panning: 128 },
+        FlanSeqCommand::SetChannelInstrument { channel: 0, index: 0 },
+        FlanSeqCommand::SetChannelPanning { channel: 1, panning: 128 },
+        FlanSeqCommand::SetChannelInstrument { channel: 1, index: 0 },
+    ]);
+    assert_eq!(events(&smf, &options).len(), events(&smf, &ConvertOptions::default()).len() + 5);
+}
+
+#[test]
+fn pan_reaches_both_extremes_and_center() {
+    assert_eq!(map_pan(0, PanLaw::Linear), 0);
+    assert_eq!(map_pan(64, PanLaw::Linear), 128);
+    assert_eq!(map_pan(127, PanLaw::Linear), 255);
+
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(10, 0))
+        .midi(0, 1, controller(10, 64))
+        .midi(0, 2, controller(10, 127))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelPanning { channel: 0, panning: 0 },
+        FlanSeqCommand::SetChannelPanning { channel: 1, panning: 128 },
+        FlanSeqCommand::SetChannelPanning { channel: 2, panning: 255 },
+    ]);
+}
+
+/// Sets the pitch bend range of a channel at tick 0 with RPN 0
+fn bend_range(song: SongBuilder, channel: u8, semitones: u8) -> SongBuilder {
+    song.midi(0, channel, controller(101, 0)).midi(0, channel, controller(100, 0)).midi(0, channel, controller(6, semitones))
+}
+
+#[test]
+fn full_bend_rounds_and_clamps() {
+    let song = bend_range(bend_range(SongBuilder::new(), 0, 24), 1, 48);
+    let smf = song
+        // 8191/8192 of 24 semitones is 2399.71 cents
+        .midi(0, 0, bend(0x1FFF))
+        .midi(48, 0, bend(-0x2000))
+        // 48 semitones is out of the i16 range either way
+        .midi(0, 1, bend(0x1FFF))
+        .midi(48, 1, bend(-0x2000))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 23997 },
+        FlanSeqCommand::SetChannelPitch { channel: 1, pitch: i16::MAX },
+        FlanSeqCommand::SetChannelPitch { channel: 0, pitch: -24000 },
+        FlanSeqCommand::SetChannelPitch { channel: 1, pitch: i16::MIN },
+    ]);
+}
+
+#[test]
+fn no_bend_drops_bends_on_listed_channels() {
+    let smf = SongBuilder::new().midi(0, 9, bend(0x1000)).midi(0, 0, bend(0x1000)).build();
+    let options = ConvertOptions { no_bend_channels: vec![9], ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 1000 }]);
+}
+
+#[test]
+fn rpn_tuning_sets_channel_pitch_offset() {
+    let smf = SongBuilder::new()
+        // Coarse tuning of +2 semitones, 64 is no transposition
+        .midi(0, 0, controller(101, 0)).midi(0, 0, controller(100, 2)).midi(0, 0, controller(6, 66))
+        // Fine tuning of +50 cents, added to later bends
+        .midi(0, 1, controller(101, 0)).midi(0, 1, controller(100, 1)).midi(0, 1, controller(6, 96))
+        .midi(48, 1, bend(0x1000))
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelTranspose { channel: 0, semitones: 2 },
+        FlanSeqCommand::SetChannelPitch { channel: 1, pitch: 500 },
+        FlanSeqCommand::SetChannelPitch { channel: 1, pitch: 1500 },
+    ]);
+}
+
+#[test]
+fn tick_multiplier_scales_tempo() {
+    let smf = SongBuilder::new().tempo(120.0, 0).note(0, 60, 100, 0, 96).build();
+    let tempo = |tick_length_multiplier| {
+        let options = ConvertOptions { tick_length_multiplier, ..ConvertOptions::default() };
+        convert_smf(&smf, &options).unwrap().into_iter().find_map(|command| match command {
+            FlanSeqCommand::SetTempo { tempo } => Some(tempo),
+            _ => None,
+        })
+    };
+    assert_eq!(tempo(midi2psx::TICK_LENGTH_MULTIPLIER), Some(256));
+    assert_eq!(tempo(midi2psx::TICK_LENGTH_MULTIPLIER * 2.0), Some(512));
+    assert_eq!(tempo(midi2psx::TICK_LENGTH_MULTIPLIER / 4.0), Some(64));
+}
+
+#[test]
+fn section_markers_give_section_table() {
+    // The start of the song begins the first section, the marker the second one
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .meta(96, MetaMessage::Marker(b"section"))
+        .note(0, 62, 100, 96, 96)
+        .build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let sections: Vec<&[FlanSeqCommand]> = commands.split(|&command| command == FlanSeqCommand::SectionStart).collect();
+    assert_eq!(sections.len(), 2, "{commands:?}");
+    assert!(sections[1].contains(&FlanSeqCommand::PlayNote { channel: 0, key: 62, velocity: 100 }));
+
+    let bytes = write_dss(&commands);
+    let first_offset = HEADER_SIZE + 2 * 4;
+    let second_offset = first_offset + serialize_commands(sections[0]).len();
+    assert_eq!(read_section_offsets(&bytes), Some(vec![first_offset, second_offset]));
+    assert_eq!(bytes[second_offset..], serialize_commands(sections[1]));
+}
+
+#[test]
+fn start_and_end_keep_only_window() {
+    // One note at the start of each 4/4 bar, 384 ticks apart, and a program change before the window
+    let mut song = SongBuilder::new().midi(0, 0, program(5));
+    for bar in 0..5 {
+        song = song.note(0, 60 + bar as u8, 100, bar * 384, 96);
+    }
+    // Still held when the window ends
+    song = song.note(1, 70, 100, 1152, 500);
+    let beat = |beats: &str| Some(beats.parse::<TimePoint>().unwrap());
+    let options = ConvertOptions { start: beat("4b"), end: beat("16b"), ..ConvertOptions::default() };
+    assert_eq!(timed_events(&song.build(), &options), [
+        (0, FlanSeqCommand::SetChannelInstrument { channel: 0, index: 5 }),
+        (0, FlanSeqCommand::PlayNote { channel: 0, key: 61, velocity: 100 }),
+        (96, FlanSeqCommand::ReleaseNote { channel: 0, key: 61 }),
+        (384, FlanSeqCommand::PlayNote { channel: 0, key: 62, velocity: 100 }),
+        (480, FlanSeqCommand::ReleaseNote { channel: 0, key: 62 }),
+        (768, FlanSeqCommand::PlayNote { channel: 0, key: 63, velocity: 100 }),
+        (768, FlanSeqCommand::PlayNote { channel: 1, key: 70, velocity: 100 }),
+        (864, FlanSeqCommand::ReleaseNote { channel: 0, key: 63 }),
+        (1152, FlanSeqCommand::ReleaseNote { channel: 1, key: 70 }),
+    ]);
+}
+
+#[test]
+fn time_point_rejects_invalid_beats() {
+    assert_eq!("2.5b".parse::<TimePoint>(), Ok(TimePoint::Beats(2.5)));
+    for beats in ["-1b", "infb", "NaNb", "b"] {
+        assert!(beats.parse::<TimePoint>().is_err(), "{beats}");
+    }
+}
+
+#[test]
+fn loop_snapshot_restores_volume() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(7, 100))
+        .meta(96, MetaMessage::Marker(b"loopStart"))
+        .note(0, 60, 100, 96, 96)
+        .midi(144, 0, controller(7, 50))
+        .meta(192, MetaMessage::Marker(b"loopEnd"))
+        .build();
+    let options = ConvertOptions { loop_snapshot: true, ..ConvertOptions::default() };
+    let commands = events(&smf, &options);
+    let loop_start = commands.iter().position(|&command| command == FlanSeqCommand::SetLoopStart).unwrap();
+    assert_eq!(commands[loop_start + 1], FlanSeqCommand::SetChannelVolume { channel: 0, volume: 100 }, "{commands:?}");
+
+    let commands = events(&smf, &ConvertOptions::default());
+    let loop_start = commands.iter().position(|&command| command == FlanSeqCommand::SetLoopStart).unwrap();
+    assert_eq!(commands[loop_start + 1], FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 }, "{commands:?}");
+}
+
+#[test]
+fn tempo_track_ignores_other_tempos() {
+    // 120 BPM is a raw tempo of 256 and 100 BPM is 307
+    let smf = SongBuilder::new().tempo(120.0, 0).track().tempo(100.0, 0).note(0, 60, 100, 0, 96).build();
+    let tempos = |tempo_track| -> Vec<FlanSeqCommand> {
+        let options = ConvertOptions { tempo_track: Some(tempo_track), ..ConvertOptions::default() };
+        convert_smf(&smf, &options).unwrap().into_iter().filter(|command| matches!(command, FlanSeqCommand::SetTempo { .. })).collect()
+    };
+    assert_eq!(tempos(0), [FlanSeqCommand::SetTempo { tempo: 256 }]);
+    assert_eq!(tempos(1), [FlanSeqCommand::SetTempo { tempo: 307 }]);
+}
+
+#[test]
+fn release_velocity_round_trips() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(100) })
+        .midi(96, 0, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(40) })
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default())[1], FlanSeqCommand::ReleaseNote { channel: 0, key: 60 });
+
+    let options = ConvertOptions { release_velocity: true, ..ConvertOptions::default() };
+    let release = events(&smf, &options)[1];
+    assert_eq!(release, FlanSeqCommand::ReleaseNoteVelocity { channel: 0, key: 60, velocity: 40 });
+    let bytes = release.serialize();
+    assert_eq!(FlanSeqCommand::deserialize(&bytes), Some((release, bytes.len())));
+}
+
+#[test]
+fn identical_volumes_leave_one_command() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(7, 100))
+        .note(0, 60, 100, 0, 96)
+        .midi(24, 0, controller(7, 100))
+        .midi(48, 0, controller(7, 100))
+        .build();
+    let commands = events(&smf, &ConvertOptions::default());
+    assert_eq!(commands.iter().filter(|command| matches!(command, FlanSeqCommand::SetChannelVolume { .. })).count(), 1, "{commands:?}");
+}
+
+#[test]
+fn overlap_policies() {
+    // The second note starts while the first is still held, and ends after it
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).note(0, 60, 90, 48, 96).build();
+    let play = |velocity| FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity };
+    let release = FlanSeqCommand::ReleaseNote { channel: 0, key: 60 };
+    let with_overlap = |overlap| timed_events(&smf, &ConvertOptions { overlap, ..ConvertOptions::default() });
+
+    assert_eq!(with_overlap(None), [(0, play(100)), (48, play(90)), (96, release), (144, release)]);
+    assert_eq!(with_overlap(Some(OverlapPolicy::Retrigger)), [(0, play(100)), (48, release), (48, play(90)), (144, release)]);
+    assert_eq!(with_overlap(Some(OverlapPolicy::Merge)), [(0, play(100)), (144, release)]);
+    assert_eq!(with_overlap(Some(OverlapPolicy::Ignore)), [(0, play(100)), (96, release)]);
+}
+
+#[test]
+fn mono_channel_releases_held_note_first() {
+    let smf = SongBuilder::new().note(2, 60, 100, 0, 96).note(2, 64, 100, 48, 96).note(3, 60, 100, 0, 96).note(3, 64, 100, 48, 96).build();
+    let options = ConvertOptions { mono_channels: vec![2], ..ConvertOptions::default() };
+    let commands = timed_events(&smf, &options);
+    let channel_2: Vec<(u32, FlanSeqCommand)> = commands.iter().copied().filter(|(_, command)| command.channel() == Some(2)).collect();
+    assert_eq!(channel_2, [
+        (0, FlanSeqCommand::PlayNote { channel: 2, key: 60, velocity: 100 }),
+        (48, FlanSeqCommand::ReleaseNote { channel: 2, key: 60 }),
+        (48, FlanSeqCommand::PlayNote { channel: 2, key: 64, velocity: 100 }),
+        (144, FlanSeqCommand::ReleaseNote { channel: 2, key: 64 }),
+    ]);
+    // Channel 3 isn't mono, so both of its notes keep playing together
+    assert!(commands.contains(&(96, FlanSeqCommand::ReleaseNote { channel: 3, key: 60 })), "{commands:?}");
+}
+
+#[test]
+fn equal_power_pan_pulls_toward_center() {
+    // Both laws put 64 at the center and keep the extremes
+    for value in [0, 64, 127] {
+        assert_eq!(map_pan(value, PanLaw::EqualPower), map_pan(value, PanLaw::Linear), "pan {value}");
+    }
+    // Halfway to the left is θ = π/8, sin θ / (sin θ + cos θ) = 0.2929 of the way from hard left, against 0.25 for linear
+    assert_eq!(map_pan(32, PanLaw::Linear), 64);
+    assert_eq!(map_pan(32, PanLaw::EqualPower), 75);
+    assert_eq!(map_pan(96, PanLaw::Linear), 193);
+    assert_eq!(map_pan(96, PanLaw::EqualPower), 182);
+
+    let smf = SongBuilder::new().midi(0, 0, controller(10, 32)).build();
+    let options = ConvertOptions { pan_law: PanLaw::EqualPower, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [FlanSeqCommand::SetChannelPanning { channel: 0, panning: 75 }]);
+}
+
+#[test]
+fn retrigger_fuses_release_and_play() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 48).note(0, 60, 90, 48, 48).note(0, 60, 80, 96, 48).build();
+    let options = ConvertOptions { retrigger_notes: true, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::RetriggerNote { channel: 0, key: 60, velocity: 90 },
+        FlanSeqCommand::RetriggerNote { channel: 0, key: 60, velocity: 80 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+
+    // A 4 byte retrigger replaces a 2 byte release and a 3 byte play
+    let plain = serialize_commands(&convert_smf(&smf, &ConvertOptions::default()).unwrap());
+    let fused = serialize_commands(&convert_smf(&smf, &options).unwrap());
+    assert_eq!(plain.len() - fused.len(), 2);
+}
+
+#[test]
+fn mapped_marker_texts_become_markers() {
+    let instrument_map = InstrumentMap::parse("[markers]\nchorus = 1\nboss = 2\n").unwrap();
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 192)
+        .meta(48, MetaMessage::Marker(b"chorus"))
+        .meta(72, MetaMessage::Marker(b"verse"))
+        .meta(96, MetaMessage::CuePoint(b" boss "))
+        .meta(120, MetaMessage::CuePoint(b"explosion"))
+        .build();
+    let options = ConvertOptions { instrument_map: Some(instrument_map), ..ConvertOptions::default() };
+    assert_eq!(timed_events(&smf, &options), [
+        (0, FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 }),
+        (48, FlanSeqCommand::Marker { id: 1 }),
+        (96, FlanSeqCommand::Marker { id: 2 }),
+        (192, FlanSeqCommand::ReleaseNote { channel: 0, key: 60 }),
+    ]);
+}
+
+#[test]
+fn portamento_switch_and_time_share_a_command() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(65, 127))
+        .midi(0, 0, controller(5, 64))
+        .midi(24, 0, controller(84, 60))
+        .note(0, 67, 100, 24, 96)
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelPortamento { channel: 0, on: true, time: 0 },
+        FlanSeqCommand::SetChannelPortamento { channel: 0, on: true, time: 64 },
+        FlanSeqCommand::SetChannelPortamentoSource { channel: 0, key: 60 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 67, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 67 },
+    ]);
+}
+
+#[test]
+fn sostenuto_and_soft_pedal_switch_at_64() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, controller(66, 64))
+        .midi(0, 0, controller(67, 127))
+        .midi(48, 0, controller(66, 63))
+        .midi(48, 0, controller(67, 0))
+        .build();
+    assert_eq!(timed_events(&smf, &ConvertOptions::default()), [
+        (0, FlanSeqCommand::SetChannelSostenuto { channel: 0, on: true }),
+        (0, FlanSeqCommand::SetChannelSoftPedal { channel: 0, on: true }),
+        (48, FlanSeqCommand::SetChannelSostenuto { channel: 0, on: false }),
+        (48, FlanSeqCommand::SetChannelSoftPedal { channel: 0, on: false }),
+    ]);
+}
+
+#[test]
+fn modulation_depth_is_passed_through() {
+    let smf = SongBuilder::new().midi(0, 3, controller(1, 0)).midi(24, 3, controller(1, 37)).midi(48, 3, controller(1, 127)).build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelModulation { channel: 3, depth: 0 },
+        FlanSeqCommand::SetChannelModulation { channel: 3, depth: 37 },
+        FlanSeqCommand::SetChannelModulation { channel: 3, depth: 127 },
+    ]);
+}
+
+#[test]
+fn default_tempo() {
+    // Without a tempo event the song plays at 120 BPM, the same as an explicit 120 BPM tempo event
+    let untimed = SongBuilder::new().note(0, 60, 100, 0, 96).build();
+    let timed = SongBuilder::new().tempo(120.0, 0).note(0, 60, 100, 0, 96).build();
+    let options = ConvertOptions::default();
+    let commands = convert_smf(&untimed, &options).unwrap();
+    assert!(matches!(commands[0], FlanSeqCommand::SetTempo { .. }), "{commands:?}");
+    assert_eq!(commands, convert_smf(&timed, &options).unwrap());
+}
+
+#[test]
+fn tempo_ramp() {
+    // A slow ritardando from 120 BPM, the raw tempo only has room for 256, 257 and 258 in this stretch
+    let mut builder = SongBuilder::new();
+    for step in 0..10 {
+        builder = builder.tempo(60_000_000.0 / (500_000 + step * 400) as f64, step * 24);
+    }
+    let smf = builder.track().note(0, 60, 100, 0, 240).build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let tempos: Vec<FlanSeqCommand> = commands.into_iter().filter(|command| matches!(command, FlanSeqCommand::SetTempo { .. })).collect();
+    assert_eq!(tempos, [256, 257, 258].map(|tempo| FlanSeqCommand::SetTempo { tempo }));
+}
+
+#[test]
+fn pitch_bend_range_change() {
+    // RPN 0 sets the bend range to 2 semitones, then to 12 partway through, each half bend uses the range set before it
+    let smf = bend_range(SongBuilder::new(), 0, 2)
+        .note(0, 60, 100, 0, 384)
+        .midi(48, 0, bend(0x1000))
+        .midi(96, 0, controller(101, 0)).midi(96, 0, controller(100, 0)).midi(96, 0, controller(6, 12))
+        .midi(192, 0, bend(0x1000))
+        .build();
+    let pitches: Vec<(u32, FlanSeqCommand)> = timed_events(&smf, &ConvertOptions::default()).into_iter()
+        .filter(|(_, command)| matches!(command, FlanSeqCommand::SetChannelPitch { .. }))
+        .collect();
+    assert_eq!(pitches, [
+        (48, FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 1000 }),
+        (192, FlanSeqCommand::SetChannelPitch { channel: 0, pitch: 6000 }),
+    ]);
+}
+
+#[test]
+fn downmix() {
+    // Channels 0 and 1 take turns, so their notes interleave on channel 0, each one cutting off the one before it
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .note(1, 64, 100, 48, 96)
+        .note(0, 67, 100, 96, 96)
+        .build();
+    let options = ConvertOptions { downmix: vec!["0,1=0".parse().unwrap()], ..ConvertOptions::default() };
+    assert_eq!(timed_events(&smf, &options), [
+        (0, FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 }),
+        (48, FlanSeqCommand::ReleaseNote { channel: 0, key: 60 }),
+        (48, FlanSeqCommand::PlayNote { channel: 0, key: 64, velocity: 100 }),
+        (96, FlanSeqCommand::ReleaseNote { channel: 0, key: 64 }),
+        (96, FlanSeqCommand::PlayNote { channel: 0, key: 67, velocity: 100 }),
+        (192, FlanSeqCommand::ReleaseNote { channel: 0, key: 67 }),
+    ]);
+}
+
+#[test]
+fn channel_conflicts() {
+    // Both tracks pick a different program for channel 0, only track 2's channel 1 volume is uncontested
+    let smf = SongBuilder::new()
+        .track().midi(0, 0, program(5)).note(0, 60, 100, 0, 96)
+        .track().midi(0, 0, program(7)).midi(0, 1, controller(7, 90))
+        .build();
+    let conflicts = midi2psx::channel_conflicts(&smf, &ConvertOptions::default());
+    assert_eq!(conflicts, [(0, [1, 2].into())].into());
+}
+
+#[test]
+fn default_drum_kit() {
+    let smf = SongBuilder::new().note(9, 36, 100, 0, 48).build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetChannelInstrument { channel: 9, index: 128 },
+        FlanSeqCommand::PlayNote { channel: 9, key: 36, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 36 },
+    ]);
+    let options = ConvertOptions { default_drum_kit: None, ..ConvertOptions::default() };
+    assert_eq!(events(&smf, &options), [
+        FlanSeqCommand::PlayNote { channel: 9, key: 36, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 9, key: 36 },
+    ]);
+}
+
+#[test]
+fn master_volume() {
+    // Universal SysEx master volume at about half, midly leaves out the leading F0
+    let smf = SongBuilder::new()
+        .event(0, TrackEventKind::SysEx(&[0x7F, 0x7F, 0x04, 0x01, 0x00, 0x40, 0xF7]))
+        .event(0, TrackEventKind::SysEx(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7]))
+        .note(0, 60, 100, 0, 96)
+        .build();
+    assert_eq!(events(&smf, &ConvertOptions::default()), [
+        FlanSeqCommand::SetMasterVolume { volume: 64 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+}
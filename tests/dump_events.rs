@@ -0,0 +1,21 @@
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{dump_events, ConvertOptions};
+
+#[test]
+fn merged_tracks_share_ticks() {
+    // Both tracks play a note at tick 96, the merged dump has them side by side
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .note(0, 62, 100, 96, 96)
+        .track()
+        .note(1, 48, 100, 96, 96)
+        .build();
+    let lines = dump_events(&smf, &ConvertOptions::default()).unwrap();
+    let at_tick = |tick: u32| lines.iter().filter(|line| line.starts_with(&format!("tick {tick:>8} "))).count();
+    assert_eq!(at_tick(0), 1, "{lines:#?}");
+    assert_eq!(at_tick(96), 3, "{lines:#?}");
+    assert_eq!(at_tick(192), 4, "{lines:#?}");
+    assert!(lines.contains(&"tick       96  channel  1  NoteOn key 48 velocity 100".to_string()), "{lines:#?}");
+}
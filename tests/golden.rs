@@ -0,0 +1,288 @@
+//! Converts small handcrafted songs and compares the output to the .dss files in tests/golden.
+//! Run with UPDATE_GOLDEN=1 to write the current output as the new golden files after an intended format change.
+
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{convert_smf, read_section_offsets, write_dss, ConvertOptions, DrumSplit, FlanSeqCommand, WaitLut};
+use midly::num::u7;
+use midly::{Format, Fps, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing};
+use std::path::PathBuf;
+
+fn check_golden(name: &str, smf: &Smf) {
+    check_golden_with(name, smf, &ConvertOptions::default());
+}
+
+fn check_golden_with(name: &str, smf: &Smf, options: &ConvertOptions) {
+    let output = write_dss(&convert_smf(smf, options).unwrap());
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", &format!("{name}.dss")].iter().collect();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, &output).unwrap();
+        return;
+    }
+    let golden = std::fs::read(&path).unwrap_or_else(|err| panic!("Failed to read {}: {err}, run with UPDATE_GOLDEN=1 to create it", path.display()));
+    assert_eq!(output, golden, "output for {name} doesn't match {}", path.display());
+}
+
+#[test]
+fn melodic() {
+    let mut builder = SongBuilder::new()
+        .midi(0, 0, MidiMessage::ProgramChange { program: u7::new(5) })
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(7), value: u7::new(90) })
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(10), value: u7::new(32) });
+    for (index, key) in [60, 64, 67, 72].into_iter().enumerate() {
+        builder = builder.note(0, key, 100, index as u32 * 96, 72);
+    }
+    check_golden("melodic", &builder.build());
+}
+
+#[test]
+fn drums() {
+    let mut builder = SongBuilder::new();
+    for (index, key) in [36, 42, 38, 42].into_iter().enumerate() {
+        builder = builder.note(9, key, 100, index as u32 * 48, 48);
+    }
+    check_golden("drums", &builder.build());
+}
+
+#[test]
+fn tempo_change() {
+    let mut builder = SongBuilder::new().tempo(120.0, 0).tempo(150.0, 384).track();
+    for index in 0..4 {
+        builder = builder.note(0, 60, 100, index * 192, 192);
+    }
+    check_golden("tempo_change", &builder.build());
+}
+
+#[test]
+fn timecode() {
+    // At 25 fps with 40 subframes a tick is a millisecond, tempo events don't change that
+    let mut smf = SongBuilder::new().tempo(150.0, 0).note(0, 60, 100, 0, 1000).note(0, 64, 100, 1500, 250).build();
+    smf.header = Header::new(Format::SingleTrack, Timing::Timecode(Fps::Fps25, 40));
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let tempos: Vec<&FlanSeqCommand> = commands.iter().filter(|command| matches!(command, FlanSeqCommand::SetTempo { .. })).collect();
+    assert_eq!(tempos, [&FlanSeqCommand::SetTempo { tempo: 49 }]);
+    assert_eq!(commands.iter().map(|command| command.wait_ticks()).sum::<u32>(), 1750);
+    check_golden("timecode", &smf);
+}
+
+#[test]
+fn pitch_bend() {
+    let mut builder = SongBuilder::new().midi(0, 0, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(100) });
+    for (index, bend) in [0.0, 0.5, 1.0, -1.0, 0.0].into_iter().enumerate() {
+        builder = builder.midi(24 + index as u32 * 24, 0, MidiMessage::PitchBend { bend: PitchBend::from_f32(bend) });
+    }
+    check_golden("pitch_bend", &builder.midi(144, 0, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(0) }).build());
+}
+
+#[test]
+fn sequential_tracks() {
+    // In format 2 each track is its own sequence in its own section, and each one starts with its own tempo and drum kit
+    let mut smf = SongBuilder::new()
+        .tempo(100.0, 0)
+        .note(0, 60, 100, 0, 96)
+        .track()
+        .note(9, 36, 100, 0, 48)
+        .note(0, 64, 100, 48, 48)
+        .build();
+    smf.header.format = Format::Sequential;
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let sections: Vec<&[FlanSeqCommand]> = commands.split(|command| *command == FlanSeqCommand::SectionStart).collect();
+    assert_eq!(sections.len(), 2, "{commands:?}");
+    let default_tempo = convert_smf(&SongBuilder::new().build(), &ConvertOptions::default()).unwrap();
+    assert_eq!(sections[0][0], FlanSeqCommand::SetTempo { tempo: 307 });
+    assert_eq!(sections[1][..2], [FlanSeqCommand::SetChannelInstrument { channel: 9, index: 128 }, default_tempo[0]]);
+    assert_eq!(read_section_offsets(&write_dss(&commands)).map(|offsets| offsets.len()), Some(2));
+    check_golden("sequential_tracks", &smf);
+}
+
+#[test]
+fn silent_tail() {
+    let mut smf = SongBuilder::new().note(0, 60, 100, 0, 96).meta(480, MetaMessage::EndOfTrack).build();
+    smf.header.format = Format::SingleTrack;
+    check_golden("silent_tail", &smf);
+}
+
+#[test]
+fn trimmed_tail() {
+    let mut smf = SongBuilder::new().note(0, 60, 100, 0, 96).meta(480, MetaMessage::EndOfTrack).build();
+    smf.header.format = Format::SingleTrack;
+    let options = ConvertOptions { trim_tail: true, ..ConvertOptions::default() };
+    // The song stops waiting at the release, instead of running on to the end of track like silent_tail
+    let commands = convert_smf(&smf, &options).unwrap();
+    let wait_ticks: u32 = commands.iter().map(FlanSeqCommand::wait_ticks).sum();
+    assert_eq!(wait_ticks, 96);
+    assert_eq!(commands.last(), Some(&FlanSeqCommand::ReleaseNote { channel: 0, key: 60 }));
+    check_golden_with("trimmed_tail", &smf, &options);
+}
+
+#[test]
+fn trimmed_leading_silence() {
+    let smf = SongBuilder::new()
+        .midi(0, 0, MidiMessage::ProgramChange { program: u7::new(3) })
+        .tempo(150.0, 240)
+        .note(0, 60, 100, 480, 96)
+        .build();
+    let options = ConvertOptions { trim_leading_silence: true, ..ConvertOptions::default() };
+    check_golden_with("trimmed_leading_silence", &smf, &options);
+}
+
+#[test]
+fn running_status() {
+    // Hardware sequencers leave out repeated status bytes and release notes with NoteOn velocity 0,
+    // including a key that's pressed twice before either press is released
+    let track: &[u8] = &[
+        0x00, 0xB0, 0x07, 0x50,
+        0x00, 0x0A, 0x20,
+        0x00, 0x90, 0x3C, 0x64,
+        0x00, 0x40, 0x64,
+        0x60, 0x3C, 0x00,
+        0x00, 0x40, 0x00,
+        0x00, 0x3C, 0x64,
+        0x30, 0x3C, 0x64,
+        0x30, 0x3C, 0x00,
+        0x30, 0x3C, 0x00,
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+    let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+    bytes.extend((track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    let smf = Smf::parse(&bytes).unwrap();
+
+    let expanded = SongBuilder::new()
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(7), value: u7::new(80) })
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(10), value: u7::new(32) })
+        .note(0, 60, 100, 0, 96)
+        .note(0, 64, 100, 0, 96)
+        .note(0, 60, 100, 96, 96)
+        .note(0, 60, 100, 144, 96)
+        .build();
+    let options = ConvertOptions::default();
+    assert_eq!(convert_smf(&smf, &options).unwrap(), convert_smf(&expanded, &options).unwrap());
+    check_golden("running_status", &smf);
+}
+
+#[test]
+fn fine_volume() {
+    // 63 with an LSB of 64 is 63.5, which only reaches full volume after the scale because of the LSB
+    let smf = SongBuilder::new()
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(7), value: u7::new(63) })
+        .midi(0, 0, MidiMessage::Controller { controller: u7::new(39), value: u7::new(64) })
+        .note(0, 60, 100, 0, 96)
+        .build();
+    let options = ConvertOptions { channel_volume_scale: [(0, 2.0)].into(), ..ConvertOptions::default() };
+    check_golden_with("fine_volume", &smf, &options);
+}
+
+#[test]
+fn split_drums() {
+    // The kick and snare each get a channel of their own, along with a copy of the drum kit, the ride cymbal's group is unused
+    let mut builder = SongBuilder::new().midi(0, 9, MidiMessage::ProgramChange { program: u7::new(8) });
+    for (index, key) in [36, 38, 36, 38].into_iter().enumerate() {
+        builder = builder.note(9, key, 100, index as u32 * 48, 48);
+    }
+    let smf = builder.note(0, 60, 100, 192, 96).build();
+    let options = ConvertOptions { split_drums: Some(DrumSplit { groups: vec![vec![35, 36], vec![38], vec![51]] }), ..ConvertOptions::default() };
+    check_golden_with("split_drums", &smf, &options);
+}
+
+#[test]
+fn ppq_normalize() {
+    let smf = SongBuilder::new().tempo(120.0, 0).note(0, 60, 100, 0, 48).note(0, 64, 100, 48, 120).build();
+    let options = ConvertOptions { ppq_normalize: Some(480), ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    let original = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let total_ticks = |commands: &[FlanSeqCommand]| commands.iter().map(|command| command.wait_ticks()).sum::<u32>();
+    assert_eq!(total_ticks(&commands), total_ticks(&original) * 5);
+    // Ticks are 5 times shorter at 480 PPQ, which is the same tick length as 600 BPM at 96 PPQ
+    let faster = SongBuilder::new().tempo(600.0, 0).build();
+    let tempos = |commands: &[FlanSeqCommand]| commands.iter().filter(|command| matches!(command, FlanSeqCommand::SetTempo { .. })).copied().collect::<Vec<_>>();
+    assert_eq!(tempos(&commands), tempos(&convert_smf(&faster, &ConvertOptions::default()).unwrap()));
+    check_golden_with("ppq_normalize", &smf, &options);
+}
+
+#[test]
+fn max_wait() {
+    // A 600 tick gap would normally take a 512, an 80 and an 8 tick wait
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 600).note(0, 62, 100, 1200, 96).build();
+    let options = ConvertOptions { max_wait_ticks: Some(100), ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    assert!(commands.iter().all(|command| command.wait_ticks() <= 100), "{commands:?}");
+    assert_eq!(commands.iter().map(|command| command.wait_ticks()).sum::<u32>(), 1296);
+    check_golden_with("max_wait", &smf, &options);
+}
+
+#[test]
+fn custom_wait_lut() {
+    // With a table of every length from 1 to 32 ticks, a 100 tick gap takes three 32 tick waits and a 4 tick wait
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 100).note(0, 62, 100, 100, 96).build();
+    let lut: Vec<String> = (1..=32).map(|ticks: u16| ticks.to_string()).collect();
+    let wait_lut: WaitLut = lut.join(",").parse().unwrap();
+    let options = ConvertOptions { wait_lut, ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    let waits: Vec<usize> = commands.iter().filter_map(|command| match *command {
+        FlanSeqCommand::WaitTicks { index_into_lut } => Some(index_into_lut),
+        _ => None,
+    }).collect();
+    assert_eq!(waits, [31, 31, 31, 3, 31, 31, 31]);
+    assert_eq!(commands.iter().map(|command| command.wait_ticks_with(&wait_lut.0)).sum::<u32>(), 196);
+    assert_ne!(commands, convert_smf(&smf, &ConvertOptions::default()).unwrap());
+    check_golden_with("custom_wait_lut", &smf, &options);
+
+    let unsorted = lut.iter().rev().cloned().collect::<Vec<_>>().join(",");
+    assert!(unsorted.parse::<WaitLut>().is_err());
+    assert!(lut[1..].join(",").parse::<WaitLut>().is_err());
+    assert!((2..=33).map(|ticks: u16| ticks.to_string()).collect::<Vec<_>>().join(",").parse::<WaitLut>().is_err());
+}
+
+#[test]
+fn note_aftertouch() {
+    let smf = SongBuilder::new()
+        .note(0, 60, 100, 0, 96)
+        .midi(48, 0, MidiMessage::Aftertouch { key: u7::new(60), vel: u7::new(90) })
+        .build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    assert!(commands.contains(&FlanSeqCommand::SetNoteAftertouch { channel: 0, key: 60, pressure: 90 }), "{commands:?}");
+    check_golden("note_aftertouch", &smf);
+}
+
+#[test]
+fn dropped_out_of_range_notes() {
+    // The two notes above the range go away along with their releases, the drum note is never affected
+    let mut builder = SongBuilder::new();
+    for (index, (channel, key)) in [(0, 60), (0, 100), (0, 72), (0, 98), (9, 100)].into_iter().enumerate() {
+        builder = builder.note(channel, key, 100, index as u32 * 48, 48);
+    }
+    let smf = builder.build();
+    let options = ConvertOptions { note_range: Some("36-96".parse().unwrap()), drop_out_of_range_notes: true, ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    let keys: Vec<(u8, u8)> = commands.iter().filter_map(|command| match *command {
+        FlanSeqCommand::PlayNote { channel, key, .. } | FlanSeqCommand::ReleaseNote { channel, key } => Some((channel, key)),
+        _ => None,
+    }).collect();
+    assert_eq!(keys, [(0, 60), (0, 60), (0, 72), (0, 72), (9, 100), (9, 100)]);
+    check_golden_with("dropped_out_of_range_notes", &smf, &options);
+}
+
+#[test]
+fn unrolled_loop() {
+    // The intro plays once, the loop twice and then the song carries on to the outro instead of jumping back
+    let smf = SongBuilder::new()
+        .note(0, 48, 100, 0, 96)
+        .meta(96, MetaMessage::Marker(b"loopStart"))
+        .note(0, 60, 100, 96, 48)
+        .note(0, 62, 100, 144, 48)
+        .meta(192, MetaMessage::Marker(b"loopEnd"))
+        .note(0, 72, 100, 192, 96)
+        .build();
+    let options = ConvertOptions { loop_count: Some(2), ..ConvertOptions::default() };
+    let commands = convert_smf(&smf, &options).unwrap();
+    assert!(!commands.iter().any(|command| matches!(command, FlanSeqCommand::SetLoopStart | FlanSeqCommand::JumpToLoopStart)), "{commands:?}");
+    let keys: Vec<u8> = commands.iter().filter_map(|command| match *command {
+        FlanSeqCommand::PlayNote { key, .. } => Some(key),
+        _ => None,
+    }).collect();
+    assert_eq!(keys, [48, 60, 62, 60, 62, 72]);
+    check_golden_with("unrolled_loop", &smf, &options);
+}
+
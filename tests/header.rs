@@ -0,0 +1,61 @@
+use midi2psx::{check_data_integrity, read_section_offsets, serialize_commands, write_dss, FlanSeqCommand, FORMAT_VERSION, HEADER_SIZE};
+
+const SONG: [FlanSeqCommand; 2] = [FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 }, FlanSeqCommand::ReleaseNote { channel: 0, key: 60 }];
+
+fn song() -> Vec<u8> {
+    write_dss(&SONG)
+}
+
+/// Bit by bit CRC-32 (IEEE), written separately from the one in the crate so both can be checked against each other
+fn reference_crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        for bit in 0..8 {
+            let feedback = (crc ^ (byte >> bit) as u32) & 1;
+            crc = (crc >> 1) ^ if feedback != 0 { 0xEDB88320 } else { 0 };
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn header_starts_with_magic_and_version() {
+    let bytes = song();
+    assert_eq!(&bytes[0..4], b"FDDS");
+    assert_eq!(bytes[4..8], FORMAT_VERSION.to_le_bytes());
+    assert_eq!(FORMAT_VERSION, 7);
+}
+
+#[test]
+fn only_versions_with_this_header_layout_are_read() {
+    let with_version = |version: u32| {
+        let mut bytes = song();
+        bytes[4..8].copy_from_slice(&version.to_le_bytes());
+        read_section_offsets(&bytes)
+    };
+    assert_eq!(with_version(FORMAT_VERSION), Some(vec![HEADER_SIZE + 4]));
+    assert_eq!(with_version(2), Some(vec![HEADER_SIZE + 4]));
+    assert_eq!(with_version(1), None);
+    assert_eq!(with_version(FORMAT_VERSION + 1), None);
+}
+
+#[test]
+fn crc_matches_recomputation() {
+    assert_eq!(reference_crc32(b"123456789"), 0xCBF43926);
+
+    let bytes = song();
+    let data_offset = HEADER_SIZE + read_u32(&bytes, 0x10) as usize;
+    let section_data = &bytes[data_offset..];
+    assert_eq!(section_data, serialize_commands(&SONG));
+    assert_eq!(read_u32(&bytes, 0x14) as usize, section_data.len());
+    assert_eq!(read_u32(&bytes, 0x18), reference_crc32(section_data));
+    assert_eq!(check_data_integrity(&bytes), Ok(()));
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(check_data_integrity(&corrupted).is_err());
+}
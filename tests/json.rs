@@ -0,0 +1,17 @@
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{commands_to_json, convert_smf, ConvertOptions, WaitLut};
+
+#[test]
+fn json_lists_commands_with_ticks() {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 96).build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&commands_to_json(&commands, &WaitLut::default())).unwrap();
+    let entries = json.as_array().unwrap();
+    assert_eq!(entries.len(), commands.len());
+
+    let release = entries.iter().find(|entry| entry["command"].get("ReleaseNote").is_some()).unwrap();
+    assert_eq!(release["command"]["ReleaseNote"], serde_json::json!({ "channel": 0, "key": 60 }));
+    assert_eq!(release["tick"], 96);
+}
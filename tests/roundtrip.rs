@@ -0,0 +1,76 @@
+use midi2psx::{FlanSeqCommand, WAIT_TICK_LUT};
+
+fn representative_commands() -> Vec<FlanSeqCommand> {
+    let mut commands = Vec::new();
+    for channel in [0, 5, 15] {
+        commands.push(FlanSeqCommand::ReleaseNote { channel, key: 60 });
+        commands.push(FlanSeqCommand::PlayNote { channel, key: 127, velocity: 1 });
+        commands.push(FlanSeqCommand::SetChannelVolume { channel, volume: 100 });
+        commands.push(FlanSeqCommand::SetChannelPanning { channel, panning: 255 });
+        commands.push(FlanSeqCommand::SetChannelPitch { channel, pitch: -12000 });
+        commands.push(FlanSeqCommand::SetChannelPitch { channel, pitch: i16::MAX });
+        commands.push(FlanSeqCommand::SetChannelInstrument { channel, index: 200 });
+        commands.push(FlanSeqCommand::SetChannelAftertouch { channel, pressure: 64 });
+        commands.push(FlanSeqCommand::SetChannelModulation { channel, depth: 127 });
+        commands.push(FlanSeqCommand::SetChannelSustain { channel, on: true });
+        commands.push(FlanSeqCommand::SetChannelSustain { channel, on: false });
+        commands.push(FlanSeqCommand::SetChannelParam { channel, param: 0x3FFF, value: 0x1234 });
+        commands.push(FlanSeqCommand::SetChannelTranspose { channel, semitones: -24 });
+        commands.push(FlanSeqCommand::SetChannelTranspose { channel, semitones: 12 });
+        commands.push(FlanSeqCommand::SetChannelSostenuto { channel, on: true });
+        commands.push(FlanSeqCommand::SetChannelSoftPedal { channel, on: true });
+        commands.push(FlanSeqCommand::SetChannelPortamento { channel, on: true, time: 64 });
+        commands.push(FlanSeqCommand::SetChannelPortamentoSource { channel, key: 48 });
+        commands.push(FlanSeqCommand::ReleaseNoteVelocity { channel, key: 60, velocity: 90 });
+        commands.push(FlanSeqCommand::RetriggerNote { channel, key: 60, velocity: 110 });
+        commands.push(FlanSeqCommand::SetNoteAftertouch { channel, key: 60, pressure: 45 });
+    }
+    for tempo in [0, 0x0FF, 0x100, 0xABC, 4095] {
+        commands.push(FlanSeqCommand::SetTempo { tempo });
+    }
+    for index_into_lut in 0..WAIT_TICK_LUT.len() {
+        commands.push(FlanSeqCommand::WaitTicks { index_into_lut });
+    }
+    commands.push(FlanSeqCommand::WaitTicksLong { ticks: 65535 });
+    commands.push(FlanSeqCommand::SetTimeSignature { numerator: 7, denominator: 8 });
+    commands.push(FlanSeqCommand::Marker { id: 42 });
+    commands.push(FlanSeqCommand::SetMasterVolume { volume: 100 });
+    commands.push(FlanSeqCommand::SetLoopStart);
+    commands.push(FlanSeqCommand::JumpToLoopStart);
+    commands
+}
+
+#[test]
+fn every_command_round_trips() {
+    for command in representative_commands() {
+        let bytes = command.serialize();
+        assert_eq!(FlanSeqCommand::deserialize(&bytes), Some((command, bytes.len())), "bytes {bytes:02X?}");
+    }
+}
+
+#[test]
+fn command_stream_round_trips() {
+    let commands = representative_commands();
+    let bytes = midi2psx::serialize_commands(&commands);
+
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (command, size) = FlanSeqCommand::deserialize(&bytes[offset..]).unwrap();
+        decoded.push(command);
+        offset += size;
+    }
+    assert_eq!(decoded, commands);
+}
+
+#[test]
+fn truncated_command_is_rejected() {
+    let bytes = FlanSeqCommand::SetChannelPitch { channel: 3, pitch: -1 }.serialize();
+    assert_eq!(FlanSeqCommand::deserialize(&bytes[..2]), None);
+}
+
+#[test]
+fn channel_aftertouch_bytes() {
+    assert_eq!(FlanSeqCommand::SetChannelAftertouch { channel: 3, pressure: 64 }.serialize(), [0x63, 64]);
+    assert_eq!(FlanSeqCommand::SetChannelAftertouch { channel: 15, pressure: 127 }.serialize(), [0x6F, 127]);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{convert_smf, track_names, write_dss, ConversionStats, ConvertOptions};
+use midly::MetaMessage;
+
+#[test]
+fn summary() {
+    // At 120 BPM and 96 PPQ a tick is 1/192 of a second, so the 384 tick song is 2 seconds long
+    let smf = SongBuilder::new()
+        .tempo(120.0, 0)
+        .note(0, 60, 100, 0, 96)
+        .note(0, 64, 100, 96, 96)
+        .note(1, 48, 100, 0, 192)
+        .note(9, 36, 100, 192, 192)
+        .build();
+    let options = ConvertOptions::default();
+    let stats = ConversionStats::from_commands_with(&convert_smf(&smf, &options).unwrap(), &options);
+    assert_eq!(stats.note_count, 4);
+    assert_eq!(stats.channels_used.len(), 3);
+    assert_eq!(stats.total_ticks, 384);
+    assert!((stats.total_seconds - 2.0).abs() < 1e-9, "{stats:?}");
+}
+
+#[test]
+fn command_counts_and_size() {
+    // 120 BPM is a raw tempo of 256 and 100 BPM is 307
+    let smf = SongBuilder::new()
+        .tempo(120.0, 0)
+        .note(0, 60, 100, 0, 96)
+        .note(0, 64, 100, 96, 96)
+        .tempo(100.0, 96)
+        .build();
+    let commands = convert_smf(&smf, &ConvertOptions::default()).unwrap();
+    let stats = ConversionStats::from_commands(&commands);
+    assert_eq!(stats.command_counts["PlayNote"], 2);
+    assert_eq!(stats.command_counts["ReleaseNote"], 2);
+    assert_eq!(stats.command_counts["SetTempo"], 2);
+    assert_eq!(stats.total_bytes, write_dss(&commands).len());
+    assert_eq!(stats.tempo_range, Some((256, 307)));
+    assert_eq!(stats.channels_used, [0].into());
+}
+
+#[test]
+fn track_names_show_up_in_stats() {
+    let smf = SongBuilder::new()
+        .meta(0, MetaMessage::TrackName(b"Conductor"))
+        .track()
+        .meta(0, MetaMessage::TrackName(b" Lead "))
+        .note(0, 60, 100, 0, 96)
+        .track()
+        .note(1, 48, 100, 0, 96)
+        .build();
+    let stats = ConversionStats { track_names: track_names(&smf), ..ConversionStats::from_commands(&convert_smf(&smf, &ConvertOptions::default()).unwrap()) };
+    assert_eq!(stats.track_names, [(0, "Conductor".to_string()), (1, "Lead".to_string())].into());
+    assert!(stats.to_string().contains("  1   Lead\n"), "{stats}");
+}
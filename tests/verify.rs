@@ -0,0 +1,43 @@
+use midi2psx::{verify_commands, FlanSeqCommand, WaitLut};
+
+fn setup() -> Vec<FlanSeqCommand> {
+    vec![
+        FlanSeqCommand::SetTempo { tempo: 200 },
+        FlanSeqCommand::SetChannelVolume { channel: 0, volume: 100 },
+    ]
+}
+
+#[test]
+fn balanced_song_passes() {
+    let mut commands = setup();
+    commands.extend([
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::WaitTicks { index_into_lut: 17 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+    assert_eq!(verify_commands(&commands, &WaitLut::default()), Vec::<String>::new());
+}
+
+#[test]
+fn hanging_note_is_reported() {
+    let mut commands = setup();
+    commands.extend([
+        FlanSeqCommand::PlayNote { channel: 0, key: 60, velocity: 100 },
+        FlanSeqCommand::PlayNote { channel: 0, key: 64, velocity: 100 },
+        FlanSeqCommand::WaitTicks { index_into_lut: 17 },
+        FlanSeqCommand::ReleaseNote { channel: 0, key: 60 },
+    ]);
+    assert_eq!(verify_commands(&commands, &WaitLut::default()), vec!["Note 64 on channel 0 is still playing at the end of the song".to_string()]);
+}
+
+#[test]
+fn missing_setup_is_reported() {
+    let commands = [
+        FlanSeqCommand::PlayNote { channel: 3, key: 60, velocity: 100 },
+        FlanSeqCommand::WaitTicks { index_into_lut: 17 },
+        FlanSeqCommand::ReleaseNote { channel: 3, key: 60 },
+    ];
+    let problems = verify_commands(&commands, &WaitLut::default());
+    assert!(problems.iter().any(|problem| problem.contains("Channel 3")), "{problems:?}");
+    assert!(problems.iter().any(|problem| problem.contains("Tempo")), "{problems:?}");
+}
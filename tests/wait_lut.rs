@@ -0,0 +1,41 @@
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{commands_to_json, convert_smf, disassemble, verify_commands, write_dss, ConvertOptions, FlanSeqCommand, WaitLut};
+
+/// Every wait length from 1 to 32 ticks, so a 100 tick gap takes three 32 tick waits and a 4 tick wait
+fn linear_wait_lut() -> WaitLut {
+    let entries: Vec<String> = (1..=32).map(|ticks: u16| ticks.to_string()).collect();
+    entries.join(",").parse().unwrap()
+}
+
+/// A note on channel 0 and one on channel 1 100 ticks later, neither channel sets its volume
+fn converted_song(wait_lut: WaitLut) -> Vec<FlanSeqCommand> {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, 100).note(1, 64, 100, 100, 96).build();
+    convert_smf(&smf, &ConvertOptions { wait_lut, ..ConvertOptions::default() }).unwrap()
+}
+
+#[test]
+fn json_ticks_use_wait_lut() {
+    let wait_lut = linear_wait_lut();
+    let json: serde_json::Value = serde_json::from_str(&commands_to_json(&converted_song(wait_lut), &wait_lut)).unwrap();
+    let second_note = json.as_array().unwrap().iter()
+        .find(|entry| entry["command"]["PlayNote"]["channel"] == 1)
+        .unwrap();
+    assert_eq!(second_note["tick"], 100);
+}
+
+#[test]
+fn disassembly_ticks_use_wait_lut() {
+    let wait_lut = linear_wait_lut();
+    let lines = disassemble(&write_dss(&converted_song(wait_lut)), &wait_lut).unwrap();
+    let second_note = lines.iter().find(|line| line.contains("PlayNote { channel: 1")).unwrap();
+    assert!(second_note.contains("tick      100"), "{second_note}");
+}
+
+#[test]
+fn verify_ticks_use_wait_lut() {
+    let wait_lut = linear_wait_lut();
+    let problems = verify_commands(&converted_song(wait_lut), &wait_lut);
+    assert!(problems.contains(&"Channel 1 plays a note at tick 100 before its volume is set".to_string()), "{problems:?}");
+}
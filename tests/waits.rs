@@ -0,0 +1,50 @@
+//! Checks the waits the converter emits for gaps of different lengths
+
+mod common;
+
+use common::SongBuilder;
+use midi2psx::{convert_smf, serialize_commands, ConvertOptions, FlanSeqCommand, WAIT_TICK_LUT};
+
+/// The waits between the start and end of a single note that lasts `gap` ticks
+fn waits_for_gap(gap: u32) -> Vec<FlanSeqCommand> {
+    let smf = SongBuilder::new().note(0, 60, 100, 0, gap).build();
+    convert_smf(&smf, &ConvertOptions::default()).unwrap().into_iter()
+        .skip_while(|command| !matches!(command, FlanSeqCommand::PlayNote { .. }))
+        .skip(1)
+        .take_while(|command| !matches!(command, FlanSeqCommand::ReleaseNote { .. }))
+        .collect()
+}
+
+/// How waits used to be picked, scanning the LUT from the longest wait down for every command
+fn linear_scan_waits(gap: u32) -> Vec<FlanSeqCommand> {
+    let mut waits = Vec::new();
+    let mut left = gap;
+    while left > 0 {
+        let index = (0..WAIT_TICK_LUT.len()).rev().find(|&index| WAIT_TICK_LUT[index] as u32 <= left).unwrap();
+        left -= WAIT_TICK_LUT[index] as u32;
+        waits.push(FlanSeqCommand::WaitTicks { index_into_lut: index });
+    }
+    waits
+}
+
+#[test]
+fn long_gap_adds_up_exactly() {
+    let waits = waits_for_gap(100000);
+    assert!(waits.iter().all(|command| matches!(command, FlanSeqCommand::WaitTicks { .. } | FlanSeqCommand::WaitTicksLong { .. })), "{waits:?}");
+    assert_eq!(waits.iter().map(FlanSeqCommand::wait_ticks).sum::<u32>(), 100000);
+}
+
+#[test]
+fn long_wait_is_smaller_than_lut_waits() {
+    let waits = waits_for_gap(5000);
+    assert_eq!(waits, [FlanSeqCommand::WaitTicksLong { ticks: 5000 }]);
+    assert_eq!(serialize_commands(&waits).len(), 3);
+    assert!(serialize_commands(&linear_scan_waits(5000)).len() > 3);
+}
+
+#[test]
+fn waits_match_linear_scan() {
+    for gap in [1, 5, 7, 31, 100, 1023, 1025, 2500, 4096] {
+        assert_eq!(waits_for_gap(gap), linear_scan_waits(gap), "gap of {gap} ticks");
+    }
+}